@@ -40,4 +40,63 @@ pub fn derive(input: TokenStream) -> TokenStream {
         }
     };
     output.into()
-}
\ No newline at end of file
+}
+#[proc_macro_derive(Diff)]
+pub fn derive_diff(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(input);
+    let DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    let fields = match data {
+        syn::Data::Struct(data) => data.fields,
+        _ => {
+            return syn::Error::new(ident.span(), "Diff can only be derived for structs")
+                .to_compile_error()
+                .into();
+        }
+    };
+    if fields.len() > 64 {
+        return syn::Error::new(fields.span(), "Diff supports at most 64 fields")
+            .to_compile_error()
+            .into();
+    }
+    let members = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        })
+        .collect::<Vec<_>>();
+    let indices = (0..members.len() as u32).collect::<Vec<_>>();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let output = quote! {
+        impl #impl_generics ::evnet::delta_sync_layer::Diff for #ident #ty_generics #where_clause {
+            fn diff(&self, baseline: Option<&Self>) -> ::evnet::delta_sync_layer::Delta {
+                let mut delta = ::evnet::delta_sync_layer::Delta::default();
+                #(
+                    if baseline.is_none_or(|baseline| baseline.#members != self.#members) {
+                        delta.push(#indices, &self.#members);
+                    }
+                )*
+                delta
+            }
+            fn apply_diff(
+                &mut self,
+                delta: &::evnet::delta_sync_layer::Delta,
+            ) -> ::evnet::delta_sync_layer::DiffResult {
+                let mut reader = delta.reader();
+                #(
+                    if let Some(field) = reader.field(#indices)? {
+                        self.#members = field;
+                    }
+                )*
+                Ok(())
+            }
+        }
+    };
+    output.into()
+}
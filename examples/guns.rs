@@ -12,7 +12,7 @@ use evnet::message_layer::SendType;
 use evnet::physics_layer::PhysicsSyncPlugin;
 use evnet::voip_layer::VoipPlugin;
 use evnet::{
-    Me, NetworkedCommandExt, NetworkingPlugins, Peer, PeerConnected, connected,
+    Me, NetworkedCommandExt, NetworkingPlugins, Peer, PeerConnected, Peers, connected,
     first_peer_connected, just_connected,
 };
 use evnet_macros::NetworkMessage;
@@ -179,20 +179,28 @@ fn on_spawn_player(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     network_entity_mapper: Res<NetworkEntityMapper>,
+    peers: Res<Peers>,
     me: Me,
 ) {
     for (peer, spawn_player) in ev.read() {
         if network_entity_mapper.contains_key(&spawn_player.0) {
             continue;
         }
-        let mut e = commands.spawn((
+        let player = (
             CharacterPhysicsBundle::new(&mut meshes, &mut materials),
             spawn_player.0,
             Transform::from_translation(spawn_player.1),
-            *peer,
-            DespawnOnDisconnect(*peer),
             TransformInterpolation,
-        ));
+        );
+        // evnet already despawns the peer's own entity when it disconnects
+        let mut e = match peers.get(peer) {
+            Some(entity) => {
+                let mut e = commands.entity(entity);
+                e.insert(player);
+                e
+            }
+            None => commands.spawn((player, *peer, DespawnOnDisconnect(*peer))),
+        };
         if me == peer {
             e.insert(LocalNet);
             e.insert(MeshMaterial3d(materials.add(Color::srgb(0.0, 0.0, 1.0))));
@@ -0,0 +1,52 @@
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use evnet::physics_layer::PhysicsSyncPlugin;
+use evnet::topology_layer::HostChanged;
+use evnet::{NetworkedCommandExt, NetworkingPlugins, PeerConnected, PeerDisconnected, connected};
+use std::time::Duration;
+
+// Runs without a window, audio or rendering:
+// cargo run --example headless_server --no-default-features -- wss://mb.v-sekai.cloud/my-room-2
+fn main() {
+    let room_url = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "wss://mb.v-sekai.cloud/my-room-2".to_string());
+    App::new()
+        .add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1.0 / 60.0,
+            ))),
+            LogPlugin::default(),
+            NetworkingPlugins,
+            PhysicsSyncPlugin::default(),
+        ))
+        .insert_resource(RoomUrl(room_url))
+        .add_systems(Startup, setup)
+        .add_systems(Update, log_connections.run_if(connected))
+        .run();
+}
+
+#[derive(Resource)]
+struct RoomUrl(String);
+
+fn setup(mut commands: Commands, room_url: Res<RoomUrl>) {
+    info!("hosting {}", room_url.0);
+    commands.connect_as_host(&room_url.0);
+}
+
+fn log_connections(
+    mut peer_connected: EventReader<PeerConnected>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    mut host_changed: EventReader<HostChanged>,
+) {
+    for ev in peer_connected.read() {
+        info!("peer connected: {:?}", ev.get());
+    }
+    for ev in peer_disconnected.read() {
+        info!("peer disconnected: {:?}", ev.get());
+    }
+    for ev in host_changed.read() {
+        info!("host is now {:?}", ev.new);
+    }
+}
@@ -1,6 +1,7 @@
 use avian3d::prelude::*;
 use bevy::prelude::*;
-use evnet::component_sync_layer::{DespawnOnDisconnect, LocalNet, NetworkEntityMapper, NetworkId};
+use evnet::authority_layer::AuthorityCommandsExt;
+use evnet::component_sync_layer::{LocalNet, NetworkEntityMapper, NetworkId, OnOwnerDisconnect};
 use evnet::event_layer::{AppExt2, NetworkEventReader, NetworkEventWriter};
 use evnet::message_layer::NetworkMessage;
 use evnet::physics_layer::PhysicsSyncPlugin;
@@ -83,7 +84,7 @@ fn changed(
 ) {
     if keys.just_pressed(KeyCode::Tab) {
         for e in remote.iter() {
-            commands.entity(e).insert(LocalNet);
+            commands.request_authority(e);
             break;
         }
     }
@@ -160,7 +161,7 @@ fn handle_spawn_cube(
             MeshMaterial3d(materials.add(Color::srgb_u8(124, 144, 255))),
             Transform::from_xyz(0.0, 4.0, 0.0),
             Cube,
-            DespawnOnDisconnect(*peer),
+            OnOwnerDisconnect::Transfer,
             TransformInterpolation,
         ));
         if peer == me.get() {
@@ -0,0 +1,260 @@
+use crate::component_sync_layer::{Authority, LocalNet, NetworkEntityMapper, NetworkId};
+use crate::message_layer::outgoing::SenderRes;
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{MeRes, Peer, PeerDisconnected, PeerRegistry, Reliability};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long we wait for the owner to answer an authority request before giving up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decides whether we hand an entity we own over to the peer asking for it.
+#[derive(Resource, Clone)]
+pub struct AuthorityPolicy(Arc<dyn Fn(&EntityRef, Peer) -> bool + Send + Sync>);
+impl AuthorityPolicy {
+    pub fn new(policy: impl Fn(&EntityRef, Peer) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(policy))
+    }
+    pub fn deny_all() -> Self {
+        Self::new(|_, _| false)
+    }
+}
+impl Default for AuthorityPolicy {
+    fn default() -> Self {
+        Self::new(|_, _| true)
+    }
+}
+
+/// We became the owner of `entity` after asking for it.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct AuthorityGranted {
+    pub entity: Entity,
+}
+
+/// The owner of `entity` refused to hand it over, or gave it to someone else.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct AuthorityDenied {
+    pub entity: Entity,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityRequest(NetworkId);
+impl NetworkMessage for AuthorityRequest {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityRefusal(NetworkId);
+impl NetworkMessage for AuthorityRefusal {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// Tells everyone who owns an entity now, the higher [`Authority`] wins if two of these cross.
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityTransfer {
+    network_id: NetworkId,
+    authority: Authority,
+}
+impl NetworkMessage for AuthorityTransfer {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// The owner each request went to, and when we stop waiting for its answer.
+#[derive(Resource, Default)]
+struct PendingRequests(HashMap<Entity, (Peer, Duration)>);
+
+pub trait AuthorityCommandsExt {
+    /// Asks the owner of `entity` for authority over it, answered with [`AuthorityGranted`] or
+    /// [`AuthorityDenied`].
+    fn request_authority(&mut self, entity: Entity);
+}
+impl AuthorityCommandsExt for Commands<'_, '_> {
+    fn request_authority(&mut self, entity: Entity) {
+        self.queue(move |world: &mut World| {
+            let Some(me) = world.get_resource::<MeRes>().map(|me| me.0) else {
+                warn!("can't request authority before connecting");
+                return;
+            };
+            let (Some(network_id), Some(authority)) = (
+                world.get::<NetworkId>(entity).copied(),
+                world.get::<Authority>(entity).copied(),
+            ) else {
+                warn!("{entity} is not networked");
+                return;
+            };
+            if world.entity(entity).contains::<LocalNet>() {
+                world.send_event(AuthorityGranted { entity });
+                return;
+            }
+            let owner = authority.owner().filter(|owner| {
+                *owner != me && world.resource::<PeerRegistry>().is_connected(owner)
+            });
+            let Some(owner) = owner else {
+                // nobody to ask
+                world.entity_mut(entity).insert(LocalNet);
+                let authority = *world.get::<Authority>(entity).unwrap();
+                if let Err(err) = world.resource::<SenderRes<AuthorityTransfer>>().0.send((
+                    AuthorityTransfer {
+                        network_id,
+                        authority,
+                    },
+                    SendType::AllButSelf,
+                )) {
+                    error!("{}", err);
+                }
+                world.send_event(AuthorityGranted { entity });
+                return;
+            };
+            let deadline = world.resource::<Time<Real>>().elapsed() + REQUEST_TIMEOUT;
+            world
+                .resource_mut::<PendingRequests>()
+                .0
+                .insert(entity, (owner, deadline));
+            if let Err(err) = world
+                .resource::<SenderRes<AuthorityRequest>>()
+                .0
+                .send((AuthorityRequest(network_id), SendType::One(owner)))
+            {
+                error!("{}", err);
+            }
+        });
+    }
+}
+
+pub struct AuthorityPlugin;
+impl Plugin for AuthorityPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkEntityMapper>();
+        app.init_resource::<AuthorityPolicy>();
+        app.init_resource::<PendingRequests>();
+        app.add_event::<AuthorityGranted>();
+        app.add_event::<AuthorityDenied>();
+        app.add_network_message(grant_requests);
+        app.add_systems(Update, abandon_requests);
+        app.add_network_message(
+            |rx: MessageReceiver<AuthorityRefusal>,
+             entity_mapper: Res<NetworkEntityMapper>,
+             mut pending: ResMut<PendingRequests>,
+             mut denied: EventWriter<AuthorityDenied>| {
+                for (AuthorityRefusal(network_id), _peer) in rx.try_iter() {
+                    let Some(entity) = entity_mapper.get(&network_id) else {
+                        continue;
+                    };
+                    if pending.0.remove(entity).is_some() {
+                        denied.send(AuthorityDenied { entity: *entity });
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<AuthorityTransfer>,
+             mut commands: Commands,
+             me: Option<Res<MeRes>>,
+             entity_mapper: Res<NetworkEntityMapper>,
+             mut pending: ResMut<PendingRequests>,
+             mut granted: EventWriter<AuthorityGranted>,
+             mut denied: EventWriter<AuthorityDenied>,
+             mut query: Query<&mut Authority>| {
+                for (transfer, _peer) in rx.try_iter() {
+                    let Some(entity) = entity_mapper.get(&transfer.network_id) else {
+                        continue;
+                    };
+                    let Ok(mut authority) = query.get_mut(*entity) else {
+                        continue;
+                    };
+                    if transfer.authority <= *authority {
+                        continue;
+                    }
+                    *authority = transfer.authority;
+                    let ours = me
+                        .as_ref()
+                        .is_some_and(|me| transfer.authority.owner() == Some(me.0));
+                    if ours {
+                        commands.entity(*entity).insert(LocalNet);
+                    } else {
+                        commands.entity(*entity).remove::<LocalNet>();
+                    }
+                    if pending.0.remove(entity).is_some() {
+                        if ours {
+                            granted.send(AuthorityGranted { entity: *entity });
+                        } else {
+                            denied.send(AuthorityDenied { entity: *entity });
+                        }
+                    }
+                }
+            },
+        );
+    }
+}
+
+fn grant_requests(
+    rx: MessageReceiver<AuthorityRequest>,
+    mut commands: Commands,
+    entity_mapper: Res<NetworkEntityMapper>,
+    policy: Res<AuthorityPolicy>,
+    owned: Query<EntityRef, With<LocalNet>>,
+    transfer_sender: MessageSender<AuthorityTransfer>,
+    refusal_sender: MessageSender<AuthorityRefusal>,
+) {
+    let mut requests = rx.try_iter().collect::<Vec<_>>();
+    // simultaneous requests go to the highest peer
+    requests.sort_by(
+        |(AuthorityRequest(a), a_peer), (AuthorityRequest(b), b_peer)| {
+            a.cmp(b).then(b_peer.cmp(a_peer))
+        },
+    );
+    let mut transferred = HashSet::new();
+    for (AuthorityRequest(network_id), peer) in requests {
+        let entity = entity_mapper
+            .get(&network_id)
+            .and_then(|entity| owned.get(*entity).ok())
+            .filter(|entity| !transferred.contains(&entity.id()));
+        let Some(entity) = entity.filter(|entity| (policy.0)(entity, peer)) else {
+            if let Err(err) =
+                refusal_sender.send((AuthorityRefusal(network_id), SendType::One(peer)))
+            {
+                error!("{}", err);
+            }
+            continue;
+        };
+        transferred.insert(entity.id());
+        let authority = Authority(entity.get::<Authority>().unwrap().0 + 1, Some(peer));
+        commands
+            .entity(entity.id())
+            .remove::<LocalNet>()
+            .insert(authority);
+        if let Err(err) = transfer_sender.send((
+            AuthorityTransfer {
+                network_id,
+                authority,
+            },
+            SendType::AllButSelf,
+        )) {
+            error!("{}", err);
+        }
+    }
+}
+
+/// Denies the requests whose owner left or never answered.
+fn abandon_requests(
+    time: Res<Time<Real>>,
+    mut pending: ResMut<PendingRequests>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    mut denied: EventWriter<AuthorityDenied>,
+) {
+    let left = peer_disconnected
+        .read()
+        .map(|ev| ev.get())
+        .collect::<HashSet<_>>();
+    let now = time.elapsed();
+    pending.0.retain(|entity, (owner, deadline)| {
+        if left.contains(owner) || *deadline <= now {
+            denied.send(AuthorityDenied { entity: *entity });
+            return false;
+        }
+        true
+    });
+}
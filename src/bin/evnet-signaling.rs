@@ -0,0 +1,24 @@
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use evnet::signaling_layer::SignalingServerPlugin;
+use std::time::Duration;
+
+// cargo run --bin evnet-signaling --features signaling -- 0.0.0.0:3536
+fn main() {
+    let mut plugin = SignalingServerPlugin::default();
+    if let Some(address) = std::env::args().nth(1) {
+        plugin.address = address
+            .parse()
+            .expect("expected a socket address like 0.0.0.0:3536");
+    }
+    App::new()
+        .add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1.0 / 60.0,
+            ))),
+            LogPlugin::default(),
+            plugin,
+        ))
+        .run();
+}
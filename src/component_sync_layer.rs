@@ -1,8 +1,16 @@
+use crate::identity_layer::PendingReclaims;
+use crate::interest_layer::{Interest, InterestGained, update_interest};
+use crate::message_layer::outgoing::SenderRes;
 use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
-use crate::{Peer, PeerDisconnected, Reliability};
+use crate::replication_layer::ReplicationAppExt;
+use crate::topology_layer::{HostChanged, migrate_host};
+use crate::{HostRes, MeRes, Peer, PeerConnected, PeerDisconnected, PeerRegistry, Reliability};
 use bevy::ecs::component::{ComponentHooks, StorageType};
+use bevy::ecs::query::{QueryData, QueryFilter, QueryItem, ReadOnlyQueryData};
+use bevy::ecs::system::EntityCommands;
 use bevy::ecs::world::DeferredWorld;
 use bevy::prelude::*;
+use bevy::utils::all_tuples;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::hash::Hash;
@@ -11,355 +19,531 @@ use std::marker::PhantomData;
 #[derive(Component)]
 pub struct DespawnOnDisconnect(pub Peer);
 
+/// What happens to an entity when the peer owning its [`Authority`] disconnects.
+///
+/// Without one, a host's entities are adopted by the next host, and everyone else's are despawned
+/// if they are marked with [`DespawnOnDisconnect`].
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum OnOwnerDisconnect {
+    #[default]
+    Despawn,
+    /// The host, or the lowest remaining peer without one, takes over.
+    Transfer,
+    /// Stays as it is without an owner until someone takes authority over it.
+    Freeze,
+}
+
 pub struct GeneralComponentSyncPlugin;
 impl Plugin for GeneralComponentSyncPlugin {
     fn build(&self, app: &mut App) {
+        app.add_event::<AuthorityGained>();
+        app.add_event::<AuthorityLost>();
+        app.add_event::<OwnerLeft>();
         app.add_systems(
             PreUpdate,
-            |mut commands: Commands,
-             mut event_reader: EventReader<PeerDisconnected>,
-             query: Query<(Entity, &DespawnOnDisconnect)>| {
-                for ev in event_reader.read() {
-                    for (e, despawn_on_disconnect) in query.iter() {
-                        if ev.0 == despawn_on_disconnect.0 {
-                            commands.entity(e).despawn_recursive();
-                        }
-                    }
-                }
-            },
+            (handle_disconnects, handle_owner_left)
+                .chain()
+                .after(migrate_host),
         );
+        app.add_systems(PostUpdate, detect_authority_changes);
     }
 }
 
-pub struct One;
-pub struct Two;
-pub struct Three;
-pub struct Four;
+/// We started owning `entity`, also triggered on it for observers.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct AuthorityGained {
+    pub entity: Entity,
+    pub previous_owner: Option<Peer>,
+    pub new_owner: Option<Peer>,
+}
 
-pub struct ComponentSyncPlugin<C, ReliabilityImplementor, Marker>(
-    PhantomData<(C, ReliabilityImplementor, Marker)>,
-);
-impl<Marker, C, ReliabilityImplementor> Default
-    for ComponentSyncPlugin<C, ReliabilityImplementor, Marker>
-{
-    fn default() -> Self {
-        Self(PhantomData)
+/// We stopped owning `entity` without it being despawned, also triggered on it for observers.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct AuthorityLost {
+    pub entity: Entity,
+    pub previous_owner: Option<Peer>,
+    pub new_owner: Option<Peer>,
+}
+
+fn detect_authority_changes(
+    mut commands: Commands,
+    mut owners: Local<HashMap<Entity, Option<Peer>>>,
+    me: Option<Res<MeRes>>,
+    changed: Query<
+        (Entity, &Authority, Option<Ref<LocalNet>>),
+        Or<(Changed<Authority>, Added<LocalNet>)>,
+    >,
+    mut lost: RemovedComponents<LocalNet>,
+    remote: Query<&Authority, Without<LocalNet>>,
+    mut removed: RemovedComponents<Authority>,
+) {
+    let me = me.map(|me| me.0);
+    for (entity, authority, local_net) in changed.iter() {
+        if local_net.is_some_and(|local_net| local_net.is_added()) {
+            let event = AuthorityGained {
+                entity,
+                previous_owner: owners.get(&entity).copied().flatten(),
+                new_owner: authority.owner(),
+            };
+            commands.send_event(event).trigger_targets(event, entity);
+        }
+        owners.insert(entity, authority.owner());
+    }
+    for entity in lost.read() {
+        // despawned, or handed straight back to us
+        let Ok(authority) = remote.get(entity) else {
+            continue;
+        };
+        let event = AuthorityLost {
+            entity,
+            previous_owner: me,
+            new_owner: authority.owner().filter(|owner| Some(*owner) != me),
+        };
+        commands.send_event(event).trigger_targets(event, entity);
+    }
+    for entity in removed.read() {
+        owners.remove(&entity);
     }
 }
-unsafe impl<Marker, C, ReliabilityImplementor> Send
-    for ComponentSyncPlugin<C, ReliabilityImplementor, Marker>
-{
+
+/// `peer` is gone for good, the entities it owned or was tied to follow their [`OnOwnerDisconnect`].
+#[derive(Event, Copy, Clone, Debug)]
+pub(crate) struct OwnerLeft {
+    pub(crate) peer: Peer,
+    /// Who took over as host, if `peer` was the host.
+    pub(crate) new_host: Option<Peer>,
 }
-unsafe impl<Marker, C, ReliabilityImplementor> Sync
-    for ComponentSyncPlugin<C, ReliabilityImplementor, Marker>
-{
+
+/// Who takes over what `peer` owned: the host, or the lowest connected peer if `peer` was the host.
+///
+/// Every peer picks the same one, so only the successor has to act.
+pub(crate) fn successor(
+    peer: Peer,
+    new_host: Option<Peer>,
+    host: Option<&HostRes>,
+    registry: &PeerRegistry,
+    me: Peer,
+) -> Peer {
+    new_host
+        .or_else(|| host.map(HostRes::get))
+        .filter(|successor| *successor != peer)
+        .or_else(|| {
+            registry
+                .connected()
+                .chain([me])
+                .filter(|other| *other != peer)
+                .min()
+        })
+        .unwrap_or(me)
 }
 
-impl<
-    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
-    C0: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-> Plugin for ComponentSyncPlugin<C0, ReliabilityImplementor, One>
-{
-    fn build(&self, app: &mut App) {
-        app.init_resource::<NetworkEntityMapper>();
-        app.add_network_message(
-            |rx: MessageReceiver<SyncMsg<ReliabilityImplementor, C0>>,
-             mut commands: Commands,
-             entity_mapper: Res<NetworkEntityMapper>,
-             mut query: Query<(&mut NetworkId, &mut Authority, &mut C0)>| {
-                for (
-                    SyncMsg {
-                        network_id,
-                        authority,
-                        data,
-                        phantom_data: _,
-                    },
-                    _peer,
-                ) in rx.try_iter()
-                {
-                    let Some(e) = entity_mapper.0.get(&network_id) else {
-                        continue;
-                    };
-                    let Ok((mut network_id2, mut authority2, mut c0)) = query.get_mut(*e) else {
-                        continue;
-                    };
-                    if authority.0 == authority2.as_ref().0 && network_id.1 > network_id2.1 {
-                        commands.entity(*e).insert(LocalNet);
-                    } else {
-                        network_id2.1 = network_id.1;
+pub(crate) fn handle_disconnects(
+    mut event_reader: EventReader<PeerDisconnected>,
+    mut host_changed: EventReader<HostChanged>,
+    mut owner_left: EventWriter<OwnerLeft>,
+    pending_reclaims: Option<Res<PendingReclaims>>,
+) {
+    let adopted = host_changed
+        .read()
+        .filter_map(|ev| Some((ev.previous?, ev.new)))
+        .collect::<HashMap<_, _>>();
+    for ev in event_reader.read() {
+        let new_host = adopted.get(&ev.0).copied();
+        // their player may still come back for these, but a host's are needed right away
+        if new_host.is_none()
+            && pending_reclaims
+                .as_ref()
+                .is_some_and(|pending| pending.contains(&ev.0))
+        {
+            continue;
+        }
+        owner_left.send(OwnerLeft {
+            peer: ev.0,
+            new_host,
+        });
+    }
+}
+
+fn handle_owner_left(
+    mut commands: Commands,
+    mut owner_left: EventReader<OwnerLeft>,
+    me: Option<Res<MeRes>>,
+    host: Option<Res<HostRes>>,
+    registry: Res<PeerRegistry>,
+    mut query: Query<(
+        Entity,
+        Option<&mut Authority>,
+        Option<&mut DespawnOnDisconnect>,
+        Option<&OnOwnerDisconnect>,
+        Has<LocalNet>,
+    )>,
+) {
+    let Some(me) = me.map(|me| me.0) else {
+        return;
+    };
+    for OwnerLeft { peer, new_host } in owner_left.read() {
+        let peer = *peer;
+        let successor = successor(peer, *new_host, host.as_deref(), &registry, me);
+        for (e, authority, despawn_on_disconnect, policy, local) in query.iter_mut() {
+            let owned = !local && authority.as_ref().is_some_and(|a| a.1 == Some(peer));
+            let tied = despawn_on_disconnect
+                .as_ref()
+                .is_some_and(|despawn_on_disconnect| despawn_on_disconnect.0 == peer);
+            if !owned && !tied {
+                continue;
+            }
+            let policy = match (policy, new_host, tied) {
+                (Some(policy), _, _) => *policy,
+                // a host's entities are adopted by the next one
+                (None, Some(_), _) => OnOwnerDisconnect::Transfer,
+                (None, None, true) => OnOwnerDisconnect::Despawn,
+                (None, None, false) => OnOwnerDisconnect::Freeze,
+            };
+            match policy {
+                OnOwnerDisconnect::Despawn => commands.entity(e).despawn_recursive(),
+                OnOwnerDisconnect::Transfer => {
+                    if let Some(mut despawn_on_disconnect) = despawn_on_disconnect.filter(|_| tied)
+                    {
+                        despawn_on_disconnect.0 = successor;
                     }
-                    if authority.0 > authority2.as_ref().0 {
-                        commands.entity(*e).remove::<LocalNet>();
-                        *authority2 = authority;
+                    if !owned {
+                        continue;
                     }
-                    *c0 = data;
-                }
-            },
-        );
-        app.add_systems(
-            PostUpdate,
-            |sender: MessageSender<SyncMsg<ReliabilityImplementor, C0>>,
-             query: Query<
-                (&NetworkId, &Authority, &C0),
-                (Or<(Changed<Authority>, Changed<C0>)>, With<LocalNet>),
-            >| {
-                for (network_id, authority, c0) in query.iter() {
-                    if let Err(err) = sender.send((
-                        SyncMsg {
-                            network_id: *network_id,
-                            authority: *authority,
-                            data: c0.clone(),
-                            phantom_data: PhantomData,
-                        },
-                        SendType::AllButSelf,
-                    )) {
-                        error!("{}", err);
+                    if successor == me {
+                        commands.entity(e).insert(LocalNet);
+                    } else if let Some(mut authority) = authority {
+                        // what the successor's LocalNet hook will make of it
+                        *authority = Authority(authority.0 + 1, Some(successor));
                     }
                 }
-            },
-        );
+                OnOwnerDisconnect::Freeze => {}
+            }
+        }
     }
 }
 
-impl<
-    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
-    C0: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-    C1: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-> Plugin for ComponentSyncPlugin<(C0, C1), ReliabilityImplementor, Two>
-{
-    fn build(&self, app: &mut App) {
-        app.init_resource::<NetworkEntityMapper>();
-        app.add_network_message(
-            |rx: MessageReceiver<SyncMsg<ReliabilityImplementor, (C0, C1)>>,
-             mut commands: Commands,
-             entity_mapper: Res<NetworkEntityMapper>,
-             mut query: Query<(&mut NetworkId, &mut Authority, &mut C0, &mut C1)>| {
-                for (
-                    SyncMsg {
-                        network_id,
-                        authority,
-                        data,
-                        phantom_data: _,
-                    },
-                    _peer,
-                ) in rx.try_iter()
-                {
-                    let Some(e) = entity_mapper.0.get(&network_id) else {
-                        continue;
-                    };
-                    let Ok((mut network_id2, mut authority2, mut c0, mut c1)) = query.get_mut(*e)
-                    else {
-                        continue;
-                    };
-                    if authority.0 == authority2.as_ref().0 && network_id.1 > network_id2.1 {
-                        commands.entity(*e).insert(LocalNet);
-                    } else {
-                        network_id2.1 = network_id.1;
-                    }
-                    if authority.0 > authority2.as_ref().0 {
-                        commands.entity(*e).remove::<LocalNet>();
-                        *authority2 = authority;
+/// Components that are synced together in a single message.
+///
+/// Implemented for tuples of up to 12 components.
+pub trait SyncBundle: Sized + Send + Sync + 'static {
+    type Query: ReadOnlyQueryData;
+    type QueryMut: QueryData;
+    type Changed: QueryFilter;
+    /// Serializes the changed components, or all of them if `all` is set.
+    fn extract(item: QueryItem<'_, Self::Query>, all: bool) -> SyncedComponents<Self>;
+    /// Writes the received components, inserting the ones the entity doesn't have (anymore).
+    fn apply(
+        item: QueryItem<'_, Self::QueryMut>,
+        entity: &mut EntityCommands,
+        components: &SyncedComponents<Self>,
+    );
+    /// Replicates removals of each component, see [`RemoteComponentRemoved`].
+    fn replicate_removals(app: &mut App);
+    /// Includes each component in the mirrors and snapshots of [`ReplicationPlugin`].
+    ///
+    /// [`ReplicationPlugin`]: crate::replication_layer::ReplicationPlugin
+    fn replicate(app: &mut App);
+}
+
+/// The serialized components of a [`SyncBundle`], bit `n` of `mask` is set if the `n`th one is present.
+///
+/// `B` keeps the message type, and so its route, distinct for every bundle.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct SyncedComponents<B> {
+    mask: u32,
+    bytes: Vec<u8>,
+    phantom_data: PhantomData<B>,
+}
+impl<B> Default for SyncedComponents<B> {
+    fn default() -> Self {
+        Self {
+            mask: 0,
+            bytes: Vec::new(),
+            phantom_data: PhantomData,
+        }
+    }
+}
+impl<B> SyncedComponents<B> {
+    fn push(&mut self, index: u32, component: &impl Serialize) {
+        self.mask |= 1 << index;
+        if let Err(err) = bincode::serialize_into(&mut self.bytes, component) {
+            error!("{}", err);
+        }
+    }
+    pub fn contains(&self, index: u32) -> bool {
+        self.mask & (1 << index) != 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.mask == 0
+    }
+}
+
+macro_rules! impl_sync_bundle {
+    ($(($C:ident, $c:ident)),*) => {
+        impl<$($C: Component + Serialize + for<'de> Deserialize<'de>),*> SyncBundle for ($($C,)*) {
+            // the owner may have removed some of them, see `replicate_removals`
+            type Query = ($(Option<Ref<'static, $C>>,)*);
+            type QueryMut = ($(Option<&'static mut $C>,)*);
+            type Changed = Or<($(Changed<$C>,)*)>;
+            #[allow(unused_assignments)]
+            fn extract(($($c,)*): QueryItem<'_, Self::Query>, all: bool) -> SyncedComponents<Self> {
+                let mut components = SyncedComponents::default();
+                let mut index = 0;
+                $(
+                    if let Some($c) = $c.filter(|$c| all || $c.is_changed()) {
+                        components.push(index, &*$c);
                     }
-                    *c0 = data.0;
-                    *c1 = data.1;
-                }
-            },
-        );
-        app.add_systems(
-            PostUpdate,
-            |sender: MessageSender<SyncMsg<ReliabilityImplementor, (C0, C1)>>,
-             query: Query<
-                (&NetworkId, &Authority, &C0, &C1),
-                (
-                    Or<(Changed<Authority>, Changed<C0>, Changed<C1>)>,
-                    With<LocalNet>,
-                ),
-            >| {
-                for (network_id, authority, c0, c1) in query.iter() {
-                    if let Err(err) = sender.send((
-                        SyncMsg {
-                            network_id: *network_id,
-                            authority: *authority,
-                            data: (c0.clone(), c1.clone()),
-                            phantom_data: PhantomData,
-                        },
-                        SendType::AllButSelf,
-                    )) {
-                        error!("{}", err);
+                    index += 1;
+                )*
+                components
+            }
+            #[allow(unused_assignments)]
+            fn apply(
+                ($($c,)*): QueryItem<'_, Self::QueryMut>,
+                entity: &mut EntityCommands,
+                components: &SyncedComponents<Self>,
+            ) {
+                let mut bytes = components.bytes.as_slice();
+                let mut index = 0;
+                $(
+                    if components.contains(index) {
+                        match bincode::deserialize_from::<_, $C>(&mut bytes) {
+                            Ok(component) => match $c {
+                                Some(mut $c) => *$c = component,
+                                None => {
+                                    entity.insert(component);
+                                }
+                            },
+                            Err(err) => {
+                                error!("{}", err);
+                                return;
+                            }
+                        }
                     }
-                }
-            },
-        );
+                    index += 1;
+                )*
+            }
+            fn replicate_removals(app: &mut App) {
+                $(replicate_removals::<$C>(app);)*
+            }
+            fn replicate(app: &mut App) {
+                $(app.replicate::<$C>();)*
+            }
+        }
+    };
+}
+all_tuples!(impl_sync_bundle, 1, 12, C, c);
+
+pub struct ComponentSyncPlugin<B, ReliabilityImplementor>(PhantomData<(B, ReliabilityImplementor)>);
+impl<B, ReliabilityImplementor> Default for ComponentSyncPlugin<B, ReliabilityImplementor> {
+    fn default() -> Self {
+        Self(PhantomData)
     }
 }
+unsafe impl<B, ReliabilityImplementor> Send for ComponentSyncPlugin<B, ReliabilityImplementor> {}
+unsafe impl<B, ReliabilityImplementor> Sync for ComponentSyncPlugin<B, ReliabilityImplementor> {}
 
-impl<
-    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
-    C0: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-    C1: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-    C2: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-> Plugin for ComponentSyncPlugin<(C0, C1, C2), ReliabilityImplementor, Three>
+impl<B: SyncBundle, ReliabilityImplementor: NetworkMessage + Send + Sync + 'static> Plugin
+    for ComponentSyncPlugin<B, ReliabilityImplementor>
 {
     fn build(&self, app: &mut App) {
         app.init_resource::<NetworkEntityMapper>();
-        app.add_network_message(
-            |rx: MessageReceiver<SyncMsg<ReliabilityImplementor, (C0, C1, C2)>>,
-             mut commands: Commands,
-             entity_mapper: Res<NetworkEntityMapper>,
-             mut query: Query<(
-                 &mut NetworkId,
-                 &mut Authority,
-                 &mut C0,
-                 &mut C1,
-                 &mut C2,
-             )>| {
-                for (
-                    SyncMsg {
-                        network_id,
-                        authority,
-                        data,
-                        phantom_data: _,
-                    },
-                    _peer,
-                ) in rx.try_iter()
-                {
-                    let Some(e) = entity_mapper.0.get(&network_id) else {
-                        continue;
-                    };
-                    let Ok((mut network_id2, mut authority2, mut c0, mut c1, mut c2)) =
-                        query.get_mut(*e)
-                    else {
-                        continue;
-                    };
-                    if authority.0 == authority2.as_ref().0 && network_id.1 > network_id2.1 {
-                        commands.entity(*e).insert(LocalNet);
-                    } else {
-                        network_id2.1 = network_id.1;
-                    }
-                    if authority.0 > authority2.as_ref().0 {
-                        commands.entity(*e).remove::<LocalNet>();
-                        *authority2 = authority;
-                    }
-                    *c0 = data.0;
-                    *c1 = data.1;
-                    *c2 = data.2;
-                }
-            },
-        );
+        // read by `send_sync` whether or not interest is managed
+        app.add_event::<InterestGained>();
+        app.add_network_message(receive_sync::<B, ReliabilityImplementor>);
         app.add_systems(
             PostUpdate,
-            |sender: MessageSender<SyncMsg<ReliabilityImplementor, (C0, C1, C2)>>,
-             query: Query<
-                (&NetworkId, &Authority, &C0, &C1, &C2),
-                (
-                    Or<(Changed<Authority>, Changed<C0>, Changed<C1>, Changed<C2>)>,
-                    With<LocalNet>,
-                ),
-            >| {
-                for (network_id, authority, c0, c1, c2) in query.iter() {
-                    if let Err(err) = sender.send((
-                        SyncMsg {
-                            network_id: *network_id,
-                            authority: *authority,
-                            data: (c0.clone(), c1.clone(), c2.clone()),
-                            phantom_data: PhantomData,
-                        },
-                        SendType::AllButSelf,
-                    )) {
-                        error!("{}", err);
-                    }
-                }
-            },
+            send_sync::<B, ReliabilityImplementor>.after(update_interest),
         );
+        B::replicate_removals(app);
+        // late joiners get the bundle along with the entity
+        B::replicate(app);
     }
 }
 
-impl<
-    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
-    C0: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-    C1: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-    C2: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-    C3: Send + Sync + Component + Serialize + for<'de> Deserialize<'de> + Clone + 'static,
-> Plugin for ComponentSyncPlugin<(C0, C1, C2, C3), ReliabilityImplementor, Four>
-{
-    fn build(&self, app: &mut App) {
-        app.init_resource::<NetworkEntityMapper>();
-        app.add_network_message(
-            |rx: MessageReceiver<SyncMsg<ReliabilityImplementor, (C0, C1, C2, C3)>>,
-             mut commands: Commands,
-             entity_mapper: Res<NetworkEntityMapper>,
-             mut query: Query<(
-                &mut NetworkId,
-                &mut Authority,
-                &mut C0,
-                &mut C1,
-                &mut C2,
-                &mut C3,
-            )>| {
-                for (
-                    SyncMsg {
-                        network_id,
-                        authority,
-                        data,
-                        phantom_data: _,
+/// The owner of `entity` removed `C` from it, as opposed to despawning it.
+#[derive(Event, Debug)]
+pub struct RemoteComponentRemoved<C> {
+    pub entity: Entity,
+    pub peer: Peer,
+    phantom_data: PhantomData<C>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ComponentRemoved<C> {
+    network_id: NetworkId,
+    phantom_data: PhantomData<C>,
+}
+impl<C: Send + Sync + 'static> NetworkMessage for ComponentRemoved<C> {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+fn replicate_removals<C: Component>(app: &mut App) {
+    // components can be part of more than one bundle
+    if app
+        .world()
+        .contains_resource::<SenderRes<ComponentRemoved<C>>>()
+    {
+        return;
+    }
+    app.add_event::<RemoteComponentRemoved<C>>();
+    app.add_network_message(
+        |rx: MessageReceiver<ComponentRemoved<C>>,
+         mut commands: Commands,
+         entity_mapper: Res<NetworkEntityMapper>,
+         mut removed: EventWriter<RemoteComponentRemoved<C>>,
+         mirrors: Query<(), (With<C>, Without<LocalNet>)>| {
+            for (ComponentRemoved { network_id, .. }, peer) in rx.try_iter() {
+                let Some(e) = entity_mapper.0.get(&network_id) else {
+                    continue;
+                };
+                if !mirrors.contains(*e) {
+                    continue;
+                }
+                commands.entity(*e).remove::<C>();
+                removed.send(RemoteComponentRemoved {
+                    entity: *e,
+                    peer,
+                    phantom_data: PhantomData,
+                });
+            }
+        },
+    );
+    app.add_systems(
+        PostUpdate,
+        (|mut removed: RemovedComponents<C>,
+          sender: MessageSender<ComponentRemoved<C>>,
+          interest: Option<Res<Interest>>,
+          // despawned entities lost their NetworkId along with `C`
+          query: Query<&NetworkId, With<LocalNet>>| {
+            for e in removed.read() {
+                let Ok(network_id) = query.get(e) else {
+                    continue;
+                };
+                if let Err(err) = sender.send((
+                    ComponentRemoved {
+                        network_id: *network_id,
+                        phantom_data: PhantomData,
                     },
-                    _peer,
-                ) in rx.try_iter()
-                {
-                    let Some(e) = entity_mapper.0.get(&network_id) else {
-                        continue;
-                    };
-                    let Ok((mut network_id2, mut authority2, mut c0, mut c1, mut c2, mut c3)) =
-                        query.get_mut(*e)
-                    else {
-                        continue;
-                    };
-                    if authority.0 == authority2.as_ref().0 && network_id.1 > network_id2.1 {
-                        commands.entity(*e).insert(LocalNet);
-                    } else {
-                        network_id2.1 = network_id.1;
-                    }
-                    if authority.0 > authority2.as_ref().0 {
-                        commands.entity(*e).remove::<LocalNet>();
-                        *authority2 = authority;
-                    }
-                    *c0 = data.0;
-                    *c1 = data.1;
-                    *c2 = data.2;
-                    *c3 = data.3;
+                    interest
+                        .as_ref()
+                        .map_or(SendType::AllButSelf, |interest| interest.send_type(e)),
+                )) {
+                    error!("{}", err);
                 }
+            }
+        })
+        .after(update_interest),
+    );
+}
+
+fn receive_sync<B: SyncBundle, ReliabilityImplementor: NetworkMessage + Send + Sync + 'static>(
+    rx: MessageReceiver<SyncMsg<ReliabilityImplementor, SyncedComponents<B>>>,
+    mut commands: Commands,
+    entity_mapper: Res<NetworkEntityMapper>,
+    mut query: Query<(&mut Authority, B::QueryMut)>,
+) {
+    for (
+        SyncMsg {
+            network_id,
+            authority,
+            data,
+            phantom_data: _,
+        },
+        _peer,
+    ) in rx.try_iter()
+    {
+        let Some(e) = entity_mapper.0.get(&network_id) else {
+            continue;
+        };
+        let Ok((mut authority2, components)) = query.get_mut(*e) else {
+            continue;
+        };
+        // updates from an owner that has since been superseded are stale
+        if authority < *authority2 {
+            continue;
+        }
+        if authority > *authority2 {
+            commands.entity(*e).remove::<LocalNet>();
+            *authority2 = authority;
+        }
+        B::apply(components, &mut commands.entity(*e), &data);
+    }
+}
+
+fn send_sync<B: SyncBundle, ReliabilityImplementor: NetworkMessage + Send + Sync + 'static>(
+    sender: MessageSender<SyncMsg<ReliabilityImplementor, SyncedComponents<B>>>,
+    interest: Option<Res<Interest>>,
+    mut peer_connected: EventReader<PeerConnected>,
+    mut interest_gained: EventReader<InterestGained>,
+    changed: Query<
+        (Entity, &NetworkId, Ref<Authority>, B::Query),
+        (Or<(Changed<Authority>, B::Changed)>, With<LocalNet>),
+    >,
+    owned: Query<(Entity, &NetworkId, &Authority, B::Query), With<LocalNet>>,
+) {
+    let send = |network_id: NetworkId,
+                authority: Authority,
+                data: SyncedComponents<B>,
+                send_type: SendType| {
+        // none of the bundle is on this entity
+        if data.is_empty() {
+            return;
+        }
+        if let Err(err) = sender.send((
+            SyncMsg {
+                network_id,
+                authority,
+                data,
+                phantom_data: PhantomData,
             },
+            send_type,
+        )) {
+            error!("{}", err);
+        }
+    };
+    // an update that got lost is made up for by the next one
+    let unreliable = ReliabilityImplementor::RELIABILITY != Reliability::Reliable;
+    for (entity, network_id, authority, components) in changed.iter() {
+        // whoever just took over needs the full picture
+        let all = unreliable || authority.is_changed();
+        send(
+            *network_id,
+            *authority,
+            B::extract(components, all),
+            interest
+                .as_ref()
+                .map_or(SendType::AllButSelf, |interest| interest.send_type(entity)),
         );
-        app.add_systems(
-            PostUpdate,
-            |sender: MessageSender<SyncMsg<ReliabilityImplementor, (C0, C1, C2, C3)>>,
-             query: Query<
-                (&NetworkId, &Authority, &C0, &C1, &C2, &C3),
-                (
-                    Or<(
-                        Changed<Authority>,
-                        Changed<C0>,
-                        Changed<C1>,
-                        Changed<C2>,
-                        Changed<C3>,
-                    )>,
-                    With<LocalNet>,
-                ),
-            >| {
-                for (network_id, authority, c0, c1, c2, c3) in query.iter() {
-                    if let Err(err) = sender.send((
-                        SyncMsg {
-                            network_id: *network_id,
-                            authority: *authority,
-                            data: (c0.clone(), c1.clone(), c2.clone(), c3.clone()),
-                            phantom_data: PhantomData,
-                        },
-                        SendType::AllButSelf,
-                    )) {
-                        error!("{}", err);
-                    }
-                }
-            },
+    }
+    // peers that just started seeing an entity missed whatever no longer changes
+    for peer_connected in peer_connected.read() {
+        for (entity, network_id, authority, components) in owned.iter() {
+            // they are told once interest is gained instead
+            if interest
+                .as_ref()
+                .is_some_and(|interest| interest.tracks(entity))
+            {
+                continue;
+            }
+            send(
+                *network_id,
+                *authority,
+                B::extract(components, true),
+                SendType::One(peer_connected.get()),
+            );
+        }
+    }
+    for InterestGained { entity, peer } in interest_gained.read() {
+        let Ok((_, network_id, authority, components)) = owned.get(*entity) else {
+            continue;
+        };
+        send(
+            *network_id,
+            *authority,
+            B::extract(components, true),
+            SendType::One(*peer),
         );
     }
 }
@@ -404,17 +588,28 @@ impl NetworkId {
     Debug,
     Default,
 )]
-pub struct Authority(u32);
+pub struct Authority(pub(crate) u32, pub(crate) Option<Peer>);
+impl Authority {
+    /// The peer that last took authority over this entity.
+    pub fn owner(&self) -> Option<Peer> {
+        self.1
+    }
+}
 impl Component for LocalNet {
     const STORAGE_TYPE: StorageType = StorageType::Table;
 
     fn register_component_hooks(hooks: &mut ComponentHooks) {
         hooks.on_insert(|mut world: DeferredWorld, targeted_entity, _component_id| {
+            let me = world.get_resource::<MeRes>().map(|me| me.0);
             let mut awa = world.entity_mut(targeted_entity);
             let mut uwu: Mut<Authority> = awa
                 .get_mut::<Authority>()
                 .expect("should have Authority<T>");
-            uwu.0 += 1;
+            // authority handed to us by its previous owner is already ours
+            if me.is_none() || uwu.1 != me {
+                uwu.0 += 1;
+                uwu.1 = me;
+            }
         });
     }
 }
@@ -436,6 +631,13 @@ where
                 .0
                 .insert(thing, targeted_entity);
         });
+        hooks.on_remove(|mut world: DeferredWorld, targeted_entity, _component_id| {
+            let network_id = *world.entity(targeted_entity).get::<NetworkId>().unwrap();
+            let mut entity_mapper = world.resource_mut::<NetworkEntityMapper>();
+            if entity_mapper.get(&network_id) == Some(&targeted_entity) {
+                entity_mapper.remove(&network_id);
+            }
+        });
     }
     fn register_required_components(
         requiree: bevy::ecs::component::ComponentId,
@@ -459,3 +661,69 @@ where
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct Health(u32);
+    #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct Label(String);
+    type Synced = (Health, Label);
+
+    fn extract(world: &mut World, entity: Entity) -> SyncedComponents<Synced> {
+        world
+            .run_system_once(move |query: Query<<Synced as SyncBundle>::Query>| {
+                Synced::extract(query.get(entity).unwrap(), true)
+            })
+            .unwrap()
+    }
+
+    fn apply(world: &mut World, entity: Entity, data: SyncedComponents<Synced>) {
+        world
+            .run_system_once(
+                move |mut commands: Commands,
+                      mut query: Query<<Synced as SyncBundle>::QueryMut>| {
+                    Synced::apply(
+                        query.get_mut(entity).unwrap(),
+                        &mut commands.entity(entity),
+                        &data,
+                    );
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn synced_components_roundtrip() {
+        let mut world = World::new();
+        let source = world.spawn((Health(3), Label("a".to_string()))).id();
+        let target = world.spawn((Health(1), Label("b".to_string()))).id();
+        let data = extract(&mut world, source);
+        assert!(data.contains(0) && data.contains(1));
+        apply(&mut world, target, data);
+        assert_eq!(world.get::<Health>(target), Some(&Health(3)));
+        assert_eq!(world.get::<Label>(target), Some(&Label("a".to_string())));
+    }
+
+    #[test]
+    fn synced_components_skip_absent_and_insert_missing() {
+        let mut world = World::new();
+        let source = world.spawn(Label("a".to_string())).id();
+        let target = world.spawn(Health(1)).id();
+        let data = extract(&mut world, source);
+        assert!(!data.contains(0) && data.contains(1));
+        apply(&mut world, target, data);
+        assert_eq!(world.get::<Health>(target), Some(&Health(1)));
+        assert_eq!(world.get::<Label>(target), Some(&Label("a".to_string())));
+    }
+
+    #[test]
+    fn entities_without_the_bundle_extract_nothing() {
+        let mut world = World::new();
+        let source = world.spawn_empty().id();
+        assert!(extract(&mut world, source).is_empty());
+    }
+}
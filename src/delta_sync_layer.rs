@@ -0,0 +1,310 @@
+use crate::component_sync_layer::{Authority, LocalNet, NetworkEntityMapper, NetworkId};
+use crate::interest_layer::{Interest, update_interest};
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{Peer, PeerRegistry, Reliability};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+
+/// How many unacknowledged states are kept per entity and peer.
+const HISTORY: usize = 32;
+/// How many frames pass between resends of a state the peer hasn't acknowledged yet.
+const RESEND_INTERVAL: u32 = 10;
+
+pub type DiffResult<T = ()> = bincode::Result<T>;
+
+/// Field-wise diffing for [`DeltaSyncPlugin`], usually derived with `evnet_macros::Diff`.
+pub trait Diff {
+    /// Serializes the fields that differ from `baseline`, or all of them without one.
+    fn diff(&self, baseline: Option<&Self>) -> Delta;
+    fn apply_diff(&mut self, delta: &Delta) -> DiffResult;
+}
+
+/// The serialized fields of a [`Diff`], bit `n` of `mask` is set if the `n`th field is present.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Delta {
+    mask: u64,
+    bytes: Vec<u8>,
+}
+impl Delta {
+    pub fn push(&mut self, index: u32, field: &impl Serialize) {
+        self.mask |= 1 << index;
+        if let Err(err) = bincode::serialize_into(&mut self.bytes, field) {
+            error!("{}", err);
+        }
+    }
+    pub fn reader(&self) -> DeltaReader<'_> {
+        DeltaReader {
+            mask: self.mask,
+            bytes: &self.bytes,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.mask == 0
+    }
+}
+
+pub struct DeltaReader<'a> {
+    mask: u64,
+    bytes: &'a [u8],
+}
+impl DeltaReader<'_> {
+    /// Reads the `index`th field if it is present, fields have to be read in order.
+    pub fn field<T: for<'de> Deserialize<'de>>(&mut self, index: u32) -> DiffResult<Option<T>> {
+        if self.mask & (1 << index) == 0 {
+            return Ok(None);
+        }
+        bincode::deserialize_from(&mut self.bytes).map(Some)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeltaMsg<ReliabilityImplementor, C> {
+    network_id: NetworkId,
+    authority: Authority,
+    sequence: u32,
+    /// The acknowledged sequence `delta` was taken against, a full snapshot if `None`.
+    baseline: Option<u32>,
+    delta: Delta,
+    phantom_data: PhantomData<(ReliabilityImplementor, C)>,
+}
+impl<
+    ReliabilityImplementor: NetworkMessage,
+    C: Send + Sync + Serialize + for<'de> Deserialize<'de> + 'static,
+> NetworkMessage for DeltaMsg<ReliabilityImplementor, C>
+{
+    const RELIABILITY: Reliability = ReliabilityImplementor::RELIABILITY;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeltaAck<C> {
+    network_id: NetworkId,
+    sequence: u32,
+    phantom_data: PhantomData<C>,
+}
+impl<C: Send + Sync + Serialize + for<'de> Deserialize<'de> + 'static> NetworkMessage
+    for DeltaAck<C>
+{
+    const RELIABILITY: Reliability = Reliability::Unreliable;
+}
+
+/// What we sent each peer, and what they acknowledged.
+struct Baseline<C> {
+    acked: Option<(u32, C)>,
+    pending: VecDeque<(u32, C)>,
+    /// The sequence we last sent.
+    sent: u32,
+}
+impl<C> Default for Baseline<C> {
+    fn default() -> Self {
+        Self {
+            acked: None,
+            pending: VecDeque::new(),
+            sent: 0,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct Baselines<C>(HashMap<(NetworkId, Peer), Baseline<C>>);
+impl<C> Default for Baselines<C> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+/// The states we received and acknowledged, deltas are applied on top of them.
+#[derive(Resource)]
+struct Received<C>(HashMap<(NetworkId, Peer), (u32, VecDeque<(u32, C)>)>);
+impl<C> Default for Received<C> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+/// Syncs `C` by sending each peer only the fields that changed since the state it last acknowledged.
+pub struct DeltaSyncPlugin<C, ReliabilityImplementor>(PhantomData<(C, ReliabilityImplementor)>);
+impl<C, ReliabilityImplementor> Default for DeltaSyncPlugin<C, ReliabilityImplementor> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+unsafe impl<C, ReliabilityImplementor> Send for DeltaSyncPlugin<C, ReliabilityImplementor> {}
+unsafe impl<C, ReliabilityImplementor> Sync for DeltaSyncPlugin<C, ReliabilityImplementor> {}
+
+impl<
+    C: Component + Diff + Clone + PartialEq + Serialize + for<'de> Deserialize<'de>,
+    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
+> Plugin for DeltaSyncPlugin<C, ReliabilityImplementor>
+{
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkEntityMapper>();
+        app.init_resource::<Baselines<C>>();
+        app.init_resource::<Received<C>>();
+        app.add_network_message(receive_deltas::<C, ReliabilityImplementor>);
+        app.add_network_message(
+            |rx: MessageReceiver<DeltaAck<C>>, mut baselines: ResMut<Baselines<C>>| {
+                for (ack, peer) in rx.try_iter() {
+                    let Some(baseline) = baselines.0.get_mut(&(ack.network_id, peer)) else {
+                        continue;
+                    };
+                    let Some(index) = baseline
+                        .pending
+                        .iter()
+                        .position(|(sequence, _)| *sequence == ack.sequence)
+                    else {
+                        continue;
+                    };
+                    // everything sent before the acknowledged state is no use as a baseline anymore
+                    baseline.acked = baseline.pending.remove(index);
+                    baseline.pending.drain(..index);
+                    // and resends of the same state don't need acknowledging either
+                    let Baseline { acked, pending, .. } = baseline;
+                    if let Some((_, acked)) = acked {
+                        pending.retain(|(_, state)| state != acked);
+                    }
+                }
+            },
+        );
+        app.add_systems(
+            PostUpdate,
+            send_deltas::<C, ReliabilityImplementor>.after(update_interest),
+        );
+    }
+}
+
+fn receive_deltas<
+    C: Component + Diff + Clone + PartialEq + Serialize + for<'de> Deserialize<'de>,
+    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
+>(
+    rx: MessageReceiver<DeltaMsg<ReliabilityImplementor, C>>,
+    mut commands: Commands,
+    entity_mapper: Res<NetworkEntityMapper>,
+    mut received: ResMut<Received<C>>,
+    ack_sender: MessageSender<DeltaAck<C>>,
+    registry: Res<PeerRegistry>,
+    mut query: Query<(&mut Authority, &mut C)>,
+) {
+    received.0.retain(|(network_id, peer), _| {
+        registry.is_connected(peer) && entity_mapper.0.contains_key(network_id)
+    });
+    for (msg, peer) in rx.try_iter() {
+        let Some(e) = entity_mapper.0.get(&msg.network_id) else {
+            continue;
+        };
+        let Ok((mut authority, mut component)) = query.get_mut(*e) else {
+            continue;
+        };
+        if msg.authority < *authority {
+            continue;
+        }
+        if msg.authority > *authority {
+            commands.entity(*e).remove::<LocalNet>();
+            *authority = msg.authority;
+        }
+        let (latest, history) = received.0.entry((msg.network_id, peer)).or_default();
+        if !history
+            .iter()
+            .any(|(sequence, _)| *sequence == msg.sequence)
+        {
+            let mut state = match msg.baseline {
+                Some(baseline) => {
+                    let Some((_, state)) =
+                        history.iter().find(|(sequence, _)| *sequence == baseline)
+                    else {
+                        warn!("missing baseline {baseline} for {:?}", msg.network_id);
+                        continue;
+                    };
+                    state.clone()
+                }
+                None => component.clone(),
+            };
+            if let Err(err) = state.apply_diff(&msg.delta) {
+                error!("{}", err);
+                continue;
+            }
+            if msg.sequence > *latest {
+                *latest = msg.sequence;
+                *component = state.clone();
+            }
+            history.push_back((msg.sequence, state));
+            if history.len() > HISTORY {
+                history.pop_front();
+            }
+        }
+        if let Err(err) = ack_sender.send((
+            DeltaAck {
+                network_id: msg.network_id,
+                sequence: msg.sequence,
+                phantom_data: PhantomData,
+            },
+            SendType::One(peer),
+        )) {
+            error!("{}", err);
+        }
+    }
+}
+
+fn send_deltas<
+    C: Component + Diff + Clone + PartialEq + Serialize + for<'de> Deserialize<'de>,
+    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
+>(
+    mut sequence: Local<u32>,
+    sender: MessageSender<DeltaMsg<ReliabilityImplementor, C>>,
+    registry: Res<PeerRegistry>,
+    entity_mapper: Res<NetworkEntityMapper>,
+    interest: Option<Res<Interest>>,
+    mut baselines: ResMut<Baselines<C>>,
+    query: Query<(Entity, &NetworkId, Ref<Authority>, Ref<C>), With<LocalNet>>,
+) {
+    let peers = registry.connected().collect::<Vec<_>>();
+    baselines.0.retain(|(network_id, peer), _| {
+        peers.contains(peer) && entity_mapper.0.contains_key(network_id)
+    });
+    *sequence += 1;
+    for (entity, network_id, authority, component) in query.iter() {
+        let changed = authority.is_changed() || component.is_changed();
+        for peer in &peers {
+            // the mirror is despawned, it starts over from a full snapshot once it's back
+            if interest
+                .as_ref()
+                .is_some_and(|interest| !interest.is_relevant(entity, *peer))
+            {
+                baselines.0.remove(&(*network_id, *peer));
+                continue;
+            }
+            let baseline = baselines.0.entry((*network_id, *peer)).or_default();
+            // keep resending every so often until the latest state is acknowledged
+            if !changed
+                && (baseline.pending.is_empty()
+                    || sequence.wrapping_sub(baseline.sent) < RESEND_INTERVAL)
+            {
+                continue;
+            }
+            baseline.sent = *sequence;
+            // the peer has likely dropped a baseline this old, start over from a full snapshot
+            let acked = baseline
+                .acked
+                .as_ref()
+                .filter(|_| baseline.pending.len() < HISTORY);
+            if let Err(err) = sender.send((
+                DeltaMsg {
+                    network_id: *network_id,
+                    authority: *authority,
+                    sequence: *sequence,
+                    baseline: acked.map(|(sequence, _)| *sequence),
+                    delta: component.diff(acked.map(|(_, acked)| acked)),
+                    phantom_data: PhantomData,
+                },
+                SendType::One(*peer),
+            )) {
+                error!("{}", err);
+            }
+            baseline.pending.push_back((*sequence, component.clone()));
+            if baseline.pending.len() > HISTORY {
+                baseline.pending.pop_front();
+            }
+        }
+    }
+}
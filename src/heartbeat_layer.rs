@@ -0,0 +1,122 @@
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{DisconnectReason, Peer, PeerRegistry, Reliability, connected};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Resource, Clone, Debug)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    /// Missed beats after which [`PeerUnresponsive`] is sent.
+    pub unresponsive_after: u32,
+    /// Silence after which the peer is disconnected with [`DisconnectReason::Timeout`].
+    ///
+    /// The peer is told and cut off for good, it has to reconnect to rejoin.
+    pub timeout: Duration,
+}
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(250),
+            unresponsive_after: 4,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct PeerUnresponsive {
+    pub peer: Peer,
+    pub missed: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Heartbeat;
+impl NetworkMessage for Heartbeat {
+    const RELIABILITY: Reliability = Reliability::Unreliable;
+}
+
+/// Tells a peer we timed it out, so it drops us too instead of simulating entities we despawned.
+#[derive(Serialize, Deserialize)]
+pub struct TimedOut;
+impl NetworkMessage for TimedOut {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Resource, Default)]
+struct LastHeard(HashMap<Peer, (Duration, bool)>);
+
+pub struct HeartbeatPlugin;
+impl Plugin for HeartbeatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HeartbeatConfig>();
+        app.init_resource::<LastHeard>();
+        app.add_event::<PeerUnresponsive>();
+        app.add_network_message(
+            |rx: MessageReceiver<Heartbeat>,
+             time: Res<Time<Real>>,
+             mut last_heard: ResMut<LastHeard>| {
+                for (_, peer) in rx.try_iter() {
+                    last_heard.0.insert(peer, (time.elapsed(), false));
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<TimedOut>, mut registry: ResMut<PeerRegistry>| {
+                for (_, peer) in rx.try_iter() {
+                    if registry.is_connected(&peer) {
+                        warn!("{peer:?} timed us out");
+                        registry.disconnect(peer, DisconnectReason::Timeout);
+                    }
+                }
+            },
+        );
+        app.add_systems(Update, (send_heartbeat, check_heartbeats).run_if(connected));
+    }
+}
+
+fn send_heartbeat(
+    time: Res<Time<Real>>,
+    config: Res<HeartbeatConfig>,
+    mut last_sent: Local<Duration>,
+    sender: MessageSender<Heartbeat>,
+) {
+    if time.elapsed() - *last_sent < config.interval {
+        return;
+    }
+    *last_sent = time.elapsed();
+    if let Err(err) = sender.send((Heartbeat, SendType::AllButSelf)) {
+        error!("{}", err);
+    }
+}
+
+fn check_heartbeats(
+    time: Res<Time<Real>>,
+    config: Res<HeartbeatConfig>,
+    mut last_heard: ResMut<LastHeard>,
+    mut registry: ResMut<PeerRegistry>,
+    mut unresponsive: EventWriter<PeerUnresponsive>,
+    sender: MessageSender<TimedOut>,
+) {
+    let now = time.elapsed();
+    let connected = registry.connected().collect::<Vec<_>>();
+    last_heard.0.retain(|peer, _| connected.contains(peer));
+    for peer in connected {
+        let (heard, warned) = last_heard.0.entry(peer).or_insert((now, false));
+        let silence = now - *heard;
+        if silence >= config.timeout {
+            warn!("{peer:?} timed out");
+            registry.disconnect(peer, DisconnectReason::Timeout);
+            if let Err(err) = sender.send((TimedOut, SendType::One(peer))) {
+                error!("{}", err);
+            }
+            continue;
+        }
+        let missed = (silence.as_secs_f64() / config.interval.as_secs_f64()) as u32;
+        if missed >= config.unresponsive_after && !*warned {
+            *warned = true;
+            unresponsive.send(PeerUnresponsive { peer, missed });
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use crate::component_sync_layer::{Authority, DespawnOnDisconnect, OwnerLeft, handle_disconnects};
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{
+    DisconnectReason, MeRes, Peer, PeerConnected, PeerDisconnected, PeerRegistry, Reliability,
+    connected, update_peers,
+};
+use bevy::prelude::*;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Identifies a player across sessions, unlike [`Peer`] which changes every time the socket connects.
+///
+/// It is the public half of a locally generated keypair, peers only accept it from whoever proves
+/// they hold the private half.
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PlayerId(pub [u8; 32]);
+
+/// Our own [`PlayerId`], loaded from [`IdentityPlugin::path`] or generated on startup.
+#[derive(Resource, Copy, Clone, Debug, Deref)]
+pub struct LocalPlayerId(pub PlayerId);
+
+#[derive(Resource)]
+struct LocalKey(SigningKey);
+
+/// Maps session [`Peer`]s to the players behind them.
+#[derive(Resource, Default)]
+pub struct PlayerIds {
+    players: HashMap<Peer, PlayerId>,
+    sessions: HashMap<PlayerId, Peer>,
+}
+impl PlayerIds {
+    pub fn player(&self, peer: &Peer) -> Option<PlayerId> {
+        self.players.get(peer).copied()
+    }
+    /// The latest session of `player`.
+    pub fn peer(&self, player: &PlayerId) -> Option<Peer> {
+        self.sessions.get(player).copied()
+    }
+}
+
+/// A player came back under a new [`Peer`] and took over the entities of its previous session.
+///
+/// Only a player reconnecting from the same process gets its entities back. To everyone else they
+/// are mirrors, which are neither sent to a restarted client nor handed back as [`LocalNet`].
+///
+/// [`LocalNet`]: crate::component_sync_layer::LocalNet
+#[derive(Event, Copy, Clone, Debug)]
+pub struct PlayerReconnected {
+    pub player: PlayerId,
+    pub previous: Peer,
+    pub peer: Peer,
+}
+
+/// A nonce the receiver has to sign to prove who it is.
+#[derive(Serialize, Deserialize)]
+pub struct IdentityChallenge([u8; 32]);
+impl NetworkMessage for IdentityChallenge {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlayerIdentity {
+    player: PlayerId,
+    /// Signs the challenge along with both sessions, so it can't be passed on to a third peer.
+    signature: Vec<u8>,
+}
+impl NetworkMessage for PlayerIdentity {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// The nonces we sent each peer and are waiting to see signed.
+#[derive(Resource, Default)]
+struct Challenges(HashMap<Peer, [u8; 32]>);
+
+/// Disconnected sessions whose entities are kept until their player reconnects or the deadline passes.
+#[derive(Resource, Default)]
+pub(crate) struct PendingReclaims(HashMap<Peer, Duration>);
+impl PendingReclaims {
+    pub(crate) fn contains(&self, peer: &Peer) -> bool {
+        self.0.contains_key(peer)
+    }
+}
+
+#[derive(Resource)]
+struct ReclaimGrace(Duration);
+
+pub struct IdentityPlugin {
+    /// Where our keypair is stored between runs, a fresh one is used every run without it.
+    ///
+    /// Keeps our [`PlayerId`] across restarts, but see [`PlayerReconnected`] for what is reclaimed.
+    /// Every instance on a machine needs its own path, they would claim the same player otherwise.
+    pub path: Option<PathBuf>,
+    /// How long a disconnected player's entities wait for them to reconnect.
+    pub reclaim_grace: Duration,
+}
+impl Default for IdentityPlugin {
+    fn default() -> Self {
+        Self {
+            path: None,
+            reclaim_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Plugin for IdentityPlugin {
+    fn build(&self, app: &mut App) {
+        let key = match &self.path {
+            Some(path) => load_or_create(path),
+            None => generate(),
+        };
+        app.insert_resource(LocalPlayerId(PlayerId(key.verifying_key().to_bytes())));
+        app.insert_resource(LocalKey(key));
+        app.insert_resource(ReclaimGrace(self.reclaim_grace));
+        app.init_resource::<PlayerIds>();
+        app.init_resource::<PendingReclaims>();
+        app.init_resource::<Challenges>();
+        app.add_event::<PlayerReconnected>();
+        app.add_network_message(
+            |rx: MessageReceiver<IdentityChallenge>,
+             me: Res<MeRes>,
+             local: Res<LocalPlayerId>,
+             key: Res<LocalKey>,
+             sender: MessageSender<PlayerIdentity>| {
+                for (IdentityChallenge(nonce), peer) in rx.try_iter() {
+                    let identity = PlayerIdentity {
+                        player: local.0,
+                        signature: key.0.sign(&signed(nonce, peer, me.0)).to_bytes().to_vec(),
+                    };
+                    if let Err(err) = sender.send((identity, SendType::One(peer))) {
+                        error!("{}", err);
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<PlayerIdentity>,
+             me: Res<MeRes>,
+             mut challenges: ResMut<Challenges>,
+             mut player_ids: ResMut<PlayerIds>,
+             mut pending: ResMut<PendingReclaims>,
+             mut reconnected: EventWriter<PlayerReconnected>,
+             registry: Res<PeerRegistry>,
+             mut authorities: Query<&mut Authority>,
+             mut despawn_on_disconnect: Query<&mut DespawnOnDisconnect>| {
+                for (identity, peer) in rx.try_iter() {
+                    let Some(nonce) = challenges.0.remove(&peer) else {
+                        warn!("{peer:?} sent an identity we did not ask for");
+                        continue;
+                    };
+                    if let Err(err) = verify(&identity, nonce, me.0, peer) {
+                        warn!("{peer:?} failed to prove its identity: {err}");
+                        continue;
+                    }
+                    let player = identity.player;
+                    // a session that timed out and came back is live again
+                    pending.0.remove(&peer);
+                    let previous = player_ids
+                        .peer(&player)
+                        .filter(|previous| *previous != peer);
+                    if let Some(previous) = previous {
+                        if registry.is_connected(&previous) {
+                            warn!("{peer:?} claims to be {player:?}, who is still connected");
+                            continue;
+                        }
+                        player_ids.players.remove(&previous);
+                        pending.0.remove(&previous);
+                        transfer(previous, peer, &mut authorities, &mut despawn_on_disconnect);
+                        reconnected.send(PlayerReconnected {
+                            player,
+                            previous,
+                            peer,
+                        });
+                    }
+                    player_ids.players.insert(peer, player);
+                    player_ids.sessions.insert(player, peer);
+                }
+            },
+        );
+        app.add_systems(
+            PreUpdate,
+            hold_reclaimable
+                .after(update_peers)
+                .before(handle_disconnects),
+        );
+        app.add_systems(
+            Update,
+            (
+                challenge_peers.run_if(connected),
+                attach_player_id,
+                follow_own_session,
+                expire_reclaims,
+            ),
+        );
+    }
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("no source of randomness");
+    bytes
+}
+
+fn generate() -> SigningKey {
+    SigningKey::from_bytes(&random_bytes())
+}
+
+fn load_or_create(path: &Path) -> SigningKey {
+    if let Some(bytes) = std::fs::read(path)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok())
+    {
+        return SigningKey::from_bytes(&bytes);
+    }
+    let key = generate();
+    if let Err(err) = std::fs::write(path, key.to_bytes()) {
+        warn!("could not store player key at {}: {err}", path.display());
+    }
+    key
+}
+
+/// What a peer signs to answer the challenge `nonce` sent by `challenger`.
+fn signed(nonce: [u8; 32], challenger: Peer, responder: Peer) -> Vec<u8> {
+    bincode::serialize(&(b"evnet-identity", nonce, challenger, responder)).unwrap()
+}
+
+fn verify(
+    identity: &PlayerIdentity,
+    nonce: [u8; 32],
+    me: Peer,
+    peer: Peer,
+) -> Result<(), ed25519_dalek::SignatureError> {
+    let key = VerifyingKey::from_bytes(&identity.player.0)?;
+    let signature = Signature::from_slice(&identity.signature)?;
+    key.verify_strict(&signed(nonce, me, peer), &signature)
+}
+
+fn transfer(
+    previous: Peer,
+    peer: Peer,
+    authorities: &mut Query<&mut Authority>,
+    despawn_on_disconnect: &mut Query<&mut DespawnOnDisconnect>,
+) {
+    for mut authority in authorities.iter_mut() {
+        if authority.1 == Some(previous) {
+            authority.1 = Some(peer);
+        }
+    }
+    for mut despawn_on_disconnect in despawn_on_disconnect.iter_mut() {
+        if despawn_on_disconnect.0 == previous {
+            despawn_on_disconnect.0 = peer;
+        }
+    }
+}
+
+fn challenge_peers(
+    mut challenges: ResMut<Challenges>,
+    mut peer_connected: EventReader<PeerConnected>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    sender: MessageSender<IdentityChallenge>,
+) {
+    for peer_connected in peer_connected.read() {
+        let nonce = random_bytes();
+        challenges.0.insert(peer_connected.get(), nonce);
+        if let Err(err) = sender.send((
+            IdentityChallenge(nonce),
+            SendType::One(peer_connected.get()),
+        )) {
+            error!("{}", err);
+        }
+    }
+    for peer_disconnected in peer_disconnected.read() {
+        challenges.0.remove(&peer_disconnected.get());
+    }
+}
+
+fn attach_player_id(
+    mut commands: Commands,
+    player_ids: Res<PlayerIds>,
+    query: Query<(Entity, &Peer), Without<PlayerId>>,
+) {
+    for (entity, peer) in query.iter() {
+        if let Some(player) = player_ids.player(peer) {
+            commands.entity(entity).insert(player);
+        }
+    }
+}
+
+/// Our own entities follow us into every new session.
+fn follow_own_session(
+    mut last: Local<Option<Peer>>,
+    me: Option<Res<MeRes>>,
+    mut authorities: Query<&mut Authority>,
+    mut despawn_on_disconnect: Query<&mut DespawnOnDisconnect>,
+) {
+    let Some(me) = me else { return };
+    if let Some(previous) = last.filter(|previous| *previous != me.0) {
+        transfer(previous, me.0, &mut authorities, &mut despawn_on_disconnect);
+    }
+    *last = Some(me.0);
+}
+
+fn hold_reclaimable(
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    mut pending: ResMut<PendingReclaims>,
+    player_ids: Res<PlayerIds>,
+    grace: Res<ReclaimGrace>,
+    time: Res<Time<Real>>,
+) {
+    for peer_disconnected in peer_disconnected.read() {
+        let peer = peer_disconnected.get();
+        if peer_disconnected.reason() != DisconnectReason::Kicked
+            && player_ids.player(&peer).is_some()
+        {
+            pending.0.insert(peer, time.elapsed() + grace.0);
+        }
+    }
+}
+
+fn expire_reclaims(
+    mut pending: ResMut<PendingReclaims>,
+    mut player_ids: ResMut<PlayerIds>,
+    mut owner_left: EventWriter<OwnerLeft>,
+    registry: Res<PeerRegistry>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed();
+    // a session readmitted before proving itself again still owns its entities
+    pending.0.retain(|peer, _| !registry.is_connected(peer));
+    let expired = pending
+        .0
+        .iter()
+        .filter(|(_, deadline)| **deadline <= now)
+        .map(|(peer, _)| *peer)
+        .collect::<Vec<_>>();
+    for peer in expired {
+        pending.0.remove(&peer);
+        if let Some(player) = player_ids.players.remove(&peer)
+            && player_ids.sessions.get(&player) == Some(&peer)
+        {
+            player_ids.sessions.remove(&player);
+        }
+        owner_left.send(OwnerLeft {
+            peer,
+            new_host: None,
+        });
+    }
+}
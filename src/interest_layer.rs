@@ -0,0 +1,187 @@
+use crate::component_sync_layer::{LocalNet, NetworkId};
+use crate::message_layer::SendType;
+use crate::{Peer, PeerRegistry, Peers};
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Decides whether a peer, and the entity carrying its [`Peer`] component if there is one, can see
+/// an entity we own.
+#[derive(Clone)]
+pub struct InterestRule(Arc<dyn Fn(EntityRef, Peer, Option<EntityRef>) -> bool + Send + Sync>);
+impl InterestRule {
+    pub fn new(
+        rule: impl Fn(EntityRef, Peer, Option<EntityRef>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(rule))
+    }
+    /// Peers whose entity is within `radius` of the entity.
+    pub fn within_distance(radius: f32) -> Self {
+        Self::new(move |entity, _peer, peer_entity| {
+            let (Some(a), Some(b)) = (
+                entity.get::<GlobalTransform>(),
+                peer_entity.and_then(|e| e.get::<GlobalTransform>()),
+            ) else {
+                return false;
+            };
+            a.translation().distance_squared(b.translation()) <= radius * radius
+        })
+    }
+    /// Peers whose entity is in the same or a neighbouring cell of a grid of `cell_size`.
+    pub fn grid(cell_size: f32) -> Self {
+        let cell = move |transform: &GlobalTransform| {
+            (transform.translation() / cell_size).floor().as_ivec3()
+        };
+        Self::new(move |entity, _peer, peer_entity| {
+            let (Some(a), Some(b)) = (
+                entity.get::<GlobalTransform>(),
+                peer_entity.and_then(|e| e.get::<GlobalTransform>()),
+            ) else {
+                return false;
+            };
+            (cell(a) - cell(b)).abs().max_element() <= 1
+        })
+    }
+    /// Peers listed in the entity's [`VisibleTo`].
+    pub fn visibility_list() -> Self {
+        Self::new(|entity, peer, _peer_entity| {
+            entity
+                .get::<VisibleTo>()
+                .is_some_and(|visible_to| visible_to.contains(&peer))
+        })
+    }
+    pub fn matches(&self, entity: EntityRef, peer: Peer, peer_entity: Option<EntityRef>) -> bool {
+        (self.0)(entity, peer, peer_entity)
+    }
+}
+
+/// Peers that can see this entity, see [`InterestRule::visibility_list`].
+#[derive(Component, Deref, DerefMut, Default, Clone, Debug)]
+pub struct VisibleTo(pub HashSet<Peer>);
+
+/// A peer can see an entity if any of these match.
+#[derive(Resource, Clone, Default)]
+pub struct InterestRules(pub Vec<InterestRule>);
+
+/// The peers each of our networked entities is relevant to.
+#[derive(Resource, Default)]
+pub struct Interest(HashMap<Entity, HashSet<Peer>>);
+impl Interest {
+    /// Whether relevance is managed for `entity`, everyone sees it otherwise.
+    pub fn tracks(&self, entity: Entity) -> bool {
+        self.0.contains_key(&entity)
+    }
+    pub fn is_relevant(&self, entity: Entity, peer: Peer) -> bool {
+        self.0
+            .get(&entity)
+            .is_none_or(|peers| peers.contains(&peer))
+    }
+    /// Where messages about `entity` should go.
+    pub fn send_type(&self, entity: Entity) -> SendType {
+        match self.0.get(&entity) {
+            Some(peers) => SendType::Many(peers.iter().copied().collect()),
+            None => SendType::AllButSelf,
+        }
+    }
+}
+
+/// `entity` became relevant to `peer`, who gets it spawned.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct InterestGained {
+    pub entity: Entity,
+    pub peer: Peer,
+}
+
+/// `entity` stopped being relevant to `peer`, who gets it despawned.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct InterestLost {
+    pub entity: Entity,
+    pub peer: Peer,
+}
+
+/// Only syncs our networked entities to the peers that can see them.
+pub struct InterestPlugin {
+    pub rules: Vec<InterestRule>,
+}
+impl InterestPlugin {
+    pub fn new(rules: impl IntoIterator<Item = InterestRule>) -> Self {
+        Self {
+            rules: rules.into_iter().collect(),
+        }
+    }
+}
+
+impl Plugin for InterestPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InterestRules(self.rules.clone()));
+        app.init_resource::<Interest>();
+        app.add_event::<InterestGained>();
+        app.add_event::<InterestLost>();
+        app.add_systems(
+            PostUpdate,
+            update_interest.after(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+pub(crate) fn update_interest(
+    rules: Res<InterestRules>,
+    peers: Res<Peers>,
+    registry: Res<PeerRegistry>,
+    mut interest: ResMut<Interest>,
+    mut commands: Commands,
+    owned: Query<EntityRef, (With<LocalNet>, With<NetworkId>)>,
+    entities: Query<EntityRef>,
+) {
+    if rules.0.is_empty() {
+        interest.0.clear();
+        return;
+    }
+    let connected = registry.connected().collect::<Vec<_>>();
+    let mut relevant = HashMap::new();
+    for entity in owned.iter() {
+        let visible = connected
+            .iter()
+            .copied()
+            .filter(|peer| {
+                let peer_entity = peers.get(peer).and_then(|e| entities.get(e).ok());
+                rules
+                    .0
+                    .iter()
+                    .any(|rule| rule.matches(entity, *peer, peer_entity))
+            })
+            .collect::<HashSet<_>>();
+        // we just took it over and can't know who the previous owner showed it to, so everyone is
+        // told, peers that already have it ignore the spawn
+        let taken_over = !interest.0.contains_key(&entity.id())
+            && entity
+                .get_ref::<NetworkId>()
+                .is_some_and(|network_id| !network_id.is_added());
+        let previous = match interest.0.remove(&entity.id()) {
+            Some(previous) => previous,
+            None if taken_over => connected.iter().copied().collect(),
+            None => HashSet::new(),
+        };
+        for peer in visible
+            .iter()
+            .filter(|peer| taken_over || !previous.contains(peer))
+        {
+            commands.send_event(InterestGained {
+                entity: entity.id(),
+                peer: *peer,
+            });
+        }
+        for peer in previous.difference(&visible) {
+            // disconnected peers have nothing left to despawn
+            if connected.contains(peer) {
+                commands.send_event(InterestLost {
+                    entity: entity.id(),
+                    peer: *peer,
+                });
+            }
+        }
+        relevant.insert(entity.id(), visible);
+    }
+    interest.0 = relevant;
+}
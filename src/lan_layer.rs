@@ -0,0 +1,235 @@
+use crate::{MeRes, NetworkedCommandExt, Peers};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+const MAGIC: [u8; 4] = *b"evnt";
+/// Advertising intervals after which a session that went quiet is forgotten.
+const SESSION_TIMEOUT_INTERVALS: u32 = 3;
+
+#[derive(Resource, Clone, Debug)]
+pub struct LanDiscoveryConfig {
+    pub port: u16,
+    pub interval: Duration,
+}
+impl Default for LanDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: 3537,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Where peers that found a session should connect to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LanRoom {
+    Url(String),
+    /// A room on a signaling server running on the advertising machine, see `SignalingServerPlugin`.
+    LocalSignaling {
+        port: u16,
+        room: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct LanAdvertisement {
+    name: String,
+    room: LanRoom,
+    players: usize,
+}
+
+#[derive(Event, Clone, Debug, PartialEq)]
+pub struct LanSessionFound {
+    pub name: String,
+    pub address: SocketAddr,
+    pub room_url: String,
+    pub players: usize,
+}
+
+#[derive(Resource)]
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    /// Every session still being advertised, and when we last heard from it.
+    sessions: HashMap<SocketAddr, (LanSessionFound, Duration)>,
+}
+impl LanDiscovery {
+    pub fn sessions(&self) -> impl Iterator<Item = &LanSessionFound> {
+        self.sessions.values().map(|(session, _)| session)
+    }
+}
+
+#[derive(Resource)]
+struct LanAdvertiser {
+    socket: UdpSocket,
+    name: String,
+    room: LanRoom,
+}
+
+pub trait LanCommandsExt {
+    /// Starts listening for sessions advertised on the local network.
+    fn discover_lan(&mut self);
+    fn stop_discovering_lan(&mut self);
+    /// Broadcasts our session to peers running [`LanCommandsExt::discover_lan`].
+    fn advertise_lan(&mut self, name: impl Into<String>, room: LanRoom);
+    fn stop_advertising_lan(&mut self);
+    fn connect_lan(&mut self, session: &LanSessionFound);
+}
+
+impl LanCommandsExt for Commands<'_, '_> {
+    fn discover_lan(&mut self) {
+        self.queue(|world: &mut World| {
+            let port = world.resource::<LanDiscoveryConfig>().port;
+            let socket = match bind((Ipv4Addr::UNSPECIFIED, port).into()) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    error!("could not listen for lan sessions on port {port}: {err}");
+                    return;
+                }
+            };
+            world.insert_resource(LanDiscovery {
+                socket,
+                sessions: HashMap::default(),
+            });
+        });
+    }
+    fn stop_discovering_lan(&mut self) {
+        self.remove_resource::<LanDiscovery>();
+    }
+    fn advertise_lan(&mut self, name: impl Into<String>, room: LanRoom) {
+        let name = name.into();
+        self.queue(move |world: &mut World| {
+            let socket = match bind((Ipv4Addr::UNSPECIFIED, 0).into()) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    error!("could not advertise lan session: {err}");
+                    return;
+                }
+            };
+            world.insert_resource(LanAdvertiser { socket, name, room });
+        });
+    }
+    fn stop_advertising_lan(&mut self) {
+        self.remove_resource::<LanAdvertiser>();
+    }
+    fn connect_lan(&mut self, session: &LanSessionFound) {
+        self.connect(&session.room_url);
+    }
+}
+
+fn bind(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // every instance on this machine listens on the same discovery port
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&address.into())?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+pub struct LanPlugin;
+impl Plugin for LanPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LanDiscoveryConfig>();
+        app.add_event::<LanSessionFound>();
+        app.add_systems(
+            Update,
+            (
+                advertise.run_if(resource_exists::<LanAdvertiser>),
+                discover.run_if(resource_exists::<LanDiscovery>),
+            ),
+        );
+    }
+}
+
+fn advertise(
+    time: Res<Time<Real>>,
+    config: Res<LanDiscoveryConfig>,
+    advertiser: Res<LanAdvertiser>,
+    me: Option<Res<MeRes>>,
+    peers: Res<Peers>,
+    mut last_sent: Local<Option<Duration>>,
+) {
+    if last_sent.is_some_and(|last_sent| time.elapsed() - last_sent < config.interval) {
+        return;
+    }
+    *last_sent = Some(time.elapsed());
+    let advertisement = LanAdvertisement {
+        name: advertiser.name.clone(),
+        room: advertiser.room.clone(),
+        players: peers.len() + me.is_some() as usize,
+    };
+    let mut packet = MAGIC.to_vec();
+    packet.extend(bincode::serialize(&advertisement).unwrap());
+    if let Err(err) = advertiser
+        .socket
+        .send_to(&packet, (Ipv4Addr::BROADCAST, config.port))
+    {
+        warn!("could not broadcast lan session: {err}");
+    }
+}
+
+fn discover(
+    time: Res<Time<Real>>,
+    config: Res<LanDiscoveryConfig>,
+    mut discovery: ResMut<LanDiscovery>,
+    mut found: EventWriter<LanSessionFound>,
+) {
+    let now = time.elapsed();
+    let mut buf = [0; 1024];
+    loop {
+        let (len, address) = match discovery.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => {
+                warn!("lan discovery failed: {err}");
+                break;
+            }
+        };
+        let Some(payload) = buf[..len].strip_prefix(&MAGIC) else {
+            continue;
+        };
+        let Ok(advertisement) = bincode::deserialize::<LanAdvertisement>(payload) else {
+            continue;
+        };
+        let room_url = match advertisement.room {
+            LanRoom::Url(url) => url,
+            LanRoom::LocalSignaling { port, room } => {
+                format!("ws://{}/{room}", SocketAddr::new(address.ip(), port))
+            }
+        };
+        let session = LanSessionFound {
+            name: advertisement.name,
+            address,
+            room_url,
+            players: advertisement.players,
+        };
+        // sessions are re-broadcast every interval, only report new or changed ones
+        if discovery.sessions.get(&address).map(|(known, _)| known) != Some(&session) {
+            found.send(session.clone());
+        }
+        discovery.sessions.insert(address, (session, now));
+    }
+    let timeout = config.interval * SESSION_TIMEOUT_INTERVALS;
+    discovery
+        .sessions
+        .retain(|_, (_, last_seen)| now - *last_seen < timeout);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_port_can_be_shared() {
+        let first = bind((Ipv4Addr::UNSPECIFIED, 0).into()).unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(bind((Ipv4Addr::UNSPECIFIED, port).into()).is_ok());
+    }
+}
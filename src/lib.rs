@@ -1,28 +1,60 @@
+pub mod authority_layer;
 pub mod component_sync_layer;
+pub mod delta_sync_layer;
 pub mod event_layer;
+pub mod heartbeat_layer;
+pub mod identity_layer;
+pub mod interest_layer;
+pub mod lan_layer;
+pub mod lobby_layer;
 pub mod message_layer;
+pub mod peer_info_layer;
 pub mod physics_layer;
+pub mod replication_layer;
+pub mod resource_sync_layer;
+#[cfg(feature = "signaling")]
+pub mod signaling_layer;
+pub mod topology_layer;
+#[cfg(feature = "voip")]
 pub mod voip_layer;
 
 use std::collections::HashMap;
 use bevy::app::{App, Plugin, PluginGroup, PluginGroupBuilder};
 use bevy::ecs::system::SystemParam;
 use bevy::prelude::{
-    Commands, Component, Event, EventReader, EventWriter, IntoSystemConfigs, Local, PreUpdate, Res,
-    ResMut, Resource, Update, not,
+    Commands, Component, Condition, DespawnRecursiveExt, Entity, Event, EventReader, EventWriter,
+    IntoSystemConfigs, Local, PreUpdate, Query, Res, ResMut, Resource, Update, World, error, not,
+    resource_exists, warn,
 };
 use bevy_matchbox::MatchboxSocket;
 use bevy_matchbox::prelude::PeerId;
+use lobby_layer::Lobby;
+use message_layer::outgoing::SenderRes;
+use message_layer::{AppExt, MessageReceiver, NetworkMessage, SendType};
 use serde::{Deserialize, Serialize};
 use std::ops::Deref;
+use topology_layer::Topology;
 use uuid::Uuid;
 
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum DisconnectReason {
+    /// The socket stopped reporting the peer.
+    Left,
+    /// The peer stopped answering heartbeats.
+    Timeout,
+    /// We kicked the peer.
+    Kicked,
+}
+
 #[derive(Event)]
-pub struct PeerDisconnected(Peer);
+pub struct PeerDisconnected(Peer, DisconnectReason);
 impl PeerDisconnected {
     pub fn get(&self) -> Peer {
         self.0
     }
+    pub fn reason(&self) -> DisconnectReason {
+        self.1
+    }
 }
 #[derive(Event)]
 pub struct PeerConnected(Peer);
@@ -34,30 +66,98 @@ impl PeerConnected {
 
 pub trait NetworkedCommandExt {
     fn connect(&mut self, room: &str);
+    /// Joins the room as the authoritative peer that every client relays through.
+    fn connect_as_host(&mut self, room: &str);
+    /// Joins the room as a client that only talks to the host.
+    fn connect_as_client(&mut self, room: &str);
+    /// Plays without a socket: we get a local identity and every message only reaches ourselves.
+    fn start_offline(&mut self);
+    /// Leaves the room, closing the socket.
+    fn disconnect(&mut self);
+    /// Cuts `peer` off and tells it to leave.
+    fn kick(&mut self, peer: Peer);
+}
+
+fn socket(room_url: &str) -> MatchboxSocket {
+    MatchboxSocket::from(
+        //example: "wss://mb.v-sekai.cloud/my-room-1"
+        bevy_matchbox::matchbox_socket::WebRtcSocketBuilder::new(room_url)
+            .add_reliable_channel()
+            .add_unreliable_channel()
+            .add_channel(matchbox_socket::ChannelConfig {
+                // UnreliableOrdered
+                ordered: true,
+                max_retransmits: Some(0),
+            })
+            .build(),
+    )
 }
 
 impl NetworkedCommandExt for Commands<'_, '_> {
     fn connect(&mut self, room_url: &str) {
-        let matchbox = MatchboxSocket::from(
-            //example: "wss://mb.v-sekai.cloud/my-room-1"
-            bevy_matchbox::matchbox_socket::WebRtcSocketBuilder::new(room_url)
-                .add_reliable_channel()
-                .add_unreliable_channel()
-                .add_channel(matchbox_socket::ChannelConfig {
-                    // UnreliableOrdered
-                    ordered: true,
-                    max_retransmits: Some(0),
-                })
-                .build(),
-        );
-        self.insert_resource(matchbox);
+        // the socket reports who we are, not an offline identity or a previous session
+        self.disconnect();
+        self.insert_resource(Topology::Mesh);
+        self.insert_resource(socket(room_url));
+    }
+    fn connect_as_host(&mut self, room_url: &str) {
+        self.disconnect();
+        self.insert_resource(Topology::Host);
+        self.insert_resource(socket(room_url));
+    }
+    fn connect_as_client(&mut self, room_url: &str) {
+        self.disconnect();
+        self.insert_resource(Topology::Client);
+        self.insert_resource(socket(room_url));
+    }
+    fn start_offline(&mut self) {
+        // lets everything that tracks the peers of a previous session clean up after them
+        self.disconnect();
+        // nobody else is around, so we are the authority on everything
+        self.insert_resource(Topology::Host);
+        self.insert_resource(MeRes(Peer(Uuid::new_v4().as_u128())));
+    }
+    fn disconnect(&mut self) {
+        self.queue(|world: &mut World| {
+            world.remove_resource::<MatchboxSocket>();
+            world.remove_resource::<MeRes>();
+            world.remove_resource::<HostRes>();
+            let registry = std::mem::take(&mut *world.resource_mut::<PeerRegistry>());
+            for peer in registry.connected() {
+                world.send_event(PeerDisconnected(peer, DisconnectReason::Left));
+            }
+        });
     }
+    fn kick(&mut self, peer: Peer) {
+        self.queue(move |world: &mut World| {
+            world
+                .resource_mut::<PeerRegistry>()
+                .disconnect(peer, DisconnectReason::Kicked);
+            if let Err(err) = world
+                .resource::<SenderRes<Kick>>()
+                .0
+                .send((Kick, SendType::One(peer)))
+            {
+                error!("{}", err);
+            }
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Kick;
+impl NetworkMessage for Kick {
+    const RELIABILITY: Reliability = Reliability::Reliable;
 }
 
 pub fn connected(me: Option<Res<MeRes>>) -> bool {
     me.is_some()
 }
 
+pub fn offline(me: Option<Res<MeRes>>, socket: Option<Res<MatchboxSocket>>) -> bool {
+    me.is_some() && socket.is_none()
+}
+
 pub fn just_connected(mut local: Local<bool>, me: Option<Res<MeRes>>) -> bool {
     if *local || me.is_none() {
         return false;
@@ -66,6 +166,10 @@ pub fn just_connected(mut local: Local<bool>, me: Option<Res<MeRes>>) -> bool {
     true
 }
 
+pub fn is_host(me: Option<Res<MeRes>>, host: Option<Res<HostRes>>) -> bool {
+    matches!((me, host), (Some(me), Some(host)) if me.0 == host.0)
+}
+
 pub fn first_peer_connected(mut local: Local<bool>, mut ev: EventReader<PeerConnected>) -> bool {
     if *local {
         return false;
@@ -92,48 +196,184 @@ impl Plugin for BaseNetworkingPlugin {
         );
         app.add_event::<PeerDisconnected>();
         app.add_event::<PeerConnected>();
+        app.init_resource::<PeerRegistry>();
+        app.init_resource::<Peers>();
         app.add_systems(
             PreUpdate,
-            (|mut disconnected: Local<Vec<Peer>>,
-              mut event_writer: EventWriter<PeerDisconnected>,
-              socket: ResMut<MatchboxSocket>| {
-                for disconnected_peer in socket.disconnected_peers() {
-                    let disconnected_peer: Peer = (*disconnected_peer).into();
-                    if !disconnected.contains(&disconnected_peer) {
-                        disconnected.push(disconnected_peer);
-                        event_writer.send(PeerDisconnected(disconnected_peer));
-                    }
-                }
-            })
-            .run_if(connected),
+            update_peers.run_if(connected.and(resource_exists::<MatchboxSocket>)),
         );
-        app.add_systems(
-            PreUpdate,
-            (|mut connected: Local<Vec<Peer>>,
-              mut event_writer: EventWriter<PeerConnected>,
-              mut socket: ResMut<MatchboxSocket>, mut buffer: Local<HashMap<Peer, u32>>| {
-                socket.update_peers();
-                for connected_peer in socket.connected_peers() {
-                    let connected_peer: Peer = (connected_peer).into();
-                    if !connected.contains(&connected_peer) {
-                        buffer.insert(connected_peer, 0);
-                        connected.push(connected_peer);
+        app.add_systems(PreUpdate, update_peer_entities.after(update_peers));
+        app.add_network_message(
+            |rx: MessageReceiver<Kick>,
+             mut commands: Commands,
+             host: Option<Res<HostRes>>,
+             lobby: Option<Res<Lobby>>| {
+                for (_, peer) in rx.try_iter() {
+                    let from_host = host.as_ref().is_some_and(|host| host.0 == peer);
+                    let from_owner = lobby.as_ref().is_some_and(|lobby| lobby.is_owner(peer));
+                    if !from_host && !from_owner {
+                        warn!("ignoring kick from {peer:?}, who neither hosts nor owns the lobby");
+                        continue;
                     }
+                    warn!("kicked by {peer:?}");
+                    commands.disconnect();
                 }
-                let mut to_remove = vec![];
-                for (p, mut u) in buffer.iter_mut() {
-                    *u += 1;
-                    if *u >= 10 {
-                        to_remove.push(*p);
-                    }
+            },
+        );
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PeerStatus {
+    /// The socket reports the peer, [`PeerConnected`] is sent once it stayed for a few frames.
+    Connecting(u32),
+    Connected,
+    /// We consider the peer gone while the socket still reports it.
+    Severed(DisconnectReason),
+}
+
+/// Tracks the lifecycle of every peer the socket reports, including peers that leave and rejoin.
+#[derive(Resource, Default, Debug)]
+pub struct PeerRegistry {
+    peers: HashMap<Peer, PeerStatus>,
+    pending_disconnects: Vec<(Peer, DisconnectReason)>,
+}
+impl PeerRegistry {
+    pub fn status(&self, peer: &Peer) -> Option<PeerStatus> {
+        self.peers.get(peer).copied()
+    }
+    pub fn is_connected(&self, peer: &Peer) -> bool {
+        self.status(peer) == Some(PeerStatus::Connected)
+    }
+    pub fn connected(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, status)| **status == PeerStatus::Connected)
+            .map(|(peer, _)| *peer)
+    }
+    pub fn kicked(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, status)| **status == PeerStatus::Severed(DisconnectReason::Kicked))
+            .map(|(peer, _)| *peer)
+    }
+    /// Peers we cut off while the socket still reports them.
+    pub fn severed(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, status)| matches!(status, PeerStatus::Severed(_)))
+            .map(|(peer, _)| *peer)
+    }
+    /// Sends [`PeerDisconnected`] for `peer` on the next update even if the socket still reports it.
+    pub fn disconnect(&mut self, peer: Peer, reason: DisconnectReason) {
+        self.pending_disconnects.push((peer, reason));
+    }
+    /// Lets a peer that was cut off connect again.
+    pub fn readmit(&mut self, peer: Peer) {
+        if let Some(status @ PeerStatus::Severed(_)) = self.peers.get_mut(&peer) {
+            *status = PeerStatus::Connecting(0);
+        }
+    }
+    /// Moves every peer along its lifecycle, given the peers the socket `current`ly reports.
+    ///
+    /// Returns the peers that just connected and the ones that just disconnected.
+    fn update(&mut self, current: &[Peer]) -> (Vec<Peer>, Vec<(Peer, DisconnectReason)>) {
+        let mut connected = Vec::new();
+        let mut disconnected = Vec::new();
+        for (peer, reason) in self.pending_disconnects.drain(..) {
+            if let Some(status) = self.peers.get_mut(&peer) {
+                if *status == PeerStatus::Connected {
+                    disconnected.push((peer, reason));
                 }
-                for p in to_remove {
-                    event_writer.send(PeerConnected(p));
-                    buffer.remove(&p);
+                *status = PeerStatus::Severed(reason);
+            }
+        }
+        self.peers.retain(|peer, status| {
+            if current.contains(peer) {
+                return true;
+            }
+            if *status == PeerStatus::Connected {
+                disconnected.push((*peer, DisconnectReason::Left));
+            }
+            false
+        });
+        for peer in current {
+            self.peers.entry(*peer).or_insert(PeerStatus::Connecting(0));
+        }
+        for (peer, status) in self.peers.iter_mut() {
+            if let PeerStatus::Connecting(frames) = status {
+                *frames += 1;
+                if *frames >= 10 {
+                    *status = PeerStatus::Connected;
+                    connected.push(*peer);
                 }
-            })
-            .run_if(connected),
-        );
+            }
+        }
+        (connected, disconnected)
+    }
+}
+
+/// Maps every connected remote [`Peer`] to the entity carrying its [`Peer`] component.
+#[derive(Resource, Default, Debug)]
+pub struct Peers(HashMap<Peer, Entity>);
+impl Peers {
+    pub fn get(&self, peer: &Peer) -> Option<Entity> {
+        self.0.get(peer).copied()
+    }
+    pub fn contains(&self, peer: &Peer) -> bool {
+        self.0.contains_key(peer)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (Peer, Entity)> + '_ {
+        self.0.iter().map(|(peer, entity)| (*peer, *entity))
+    }
+    pub fn peers(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.0.keys().copied()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn update_peer_entities(
+    mut commands: Commands,
+    mut peers: ResMut<Peers>,
+    mut peer_connected: EventReader<PeerConnected>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    existing: Query<(Entity, &Peer)>,
+) {
+    for ev in peer_connected.read() {
+        // an entity spawned by the app for this peer before it finished connecting is reused
+        let entity = existing
+            .iter()
+            .find(|(_, peer)| **peer == ev.0)
+            .map(|(entity, _)| entity)
+            .unwrap_or_else(|| commands.spawn(ev.0).id());
+        peers.0.insert(ev.0, entity);
+    }
+    for ev in peer_disconnected.read() {
+        if let Some(entity) = peers.0.remove(&ev.0).and_then(|e| commands.get_entity(e)) {
+            entity.despawn_recursive();
+        }
+    }
+}
+
+pub(crate) fn update_peers(
+    mut registry: ResMut<PeerRegistry>,
+    mut peer_connected: EventWriter<PeerConnected>,
+    mut peer_disconnected: EventWriter<PeerDisconnected>,
+    mut socket: ResMut<MatchboxSocket>,
+) {
+    socket.update_peers();
+    let current = socket.connected_peers().map(Peer::from).collect::<Vec<_>>();
+    let (connected, disconnected) = registry.update(&current);
+    for (peer, reason) in disconnected {
+        peer_disconnected.send(PeerDisconnected(peer, reason));
+    }
+    for peer in connected {
+        peer_connected.send(PeerConnected(peer));
     }
 }
 
@@ -144,7 +384,12 @@ impl PluginGroup for NetworkingPlugins {
         PluginGroupBuilder::start::<Self>()
             .add(BaseNetworkingPlugin)
             .add(message_layer::MessageLayerPlugin)
+            .add(topology_layer::TopologyPlugin)
+            .add(heartbeat_layer::HeartbeatPlugin)
+            .add(lobby_layer::LobbyPlugin)
+            .add(lan_layer::LanPlugin)
             .add(component_sync_layer::GeneralComponentSyncPlugin)
+            .add(authority_layer::AuthorityPlugin)
     }
 }
 
@@ -167,9 +412,19 @@ impl From<matchbox_socket::PeerId> for Peer {
 #[derive(Resource, Component, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct MeRes(Peer);
 
+/// The authoritative peer when running in client-server mode.
+#[derive(Resource, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct HostRes(Peer);
+impl HostRes {
+    pub fn get(&self) -> Peer {
+        self.0
+    }
+}
+
 #[derive(SystemParam)]
 pub struct Me<'w> {
     me_res: Res<'w, MeRes>,
+    host: Option<Res<'w, HostRes>>,
 }
 impl PartialEq<Peer> for Me<'_> {
     fn eq(&self, other: &Peer) -> bool {
@@ -194,6 +449,14 @@ impl Me<'_> {
     pub fn get(&self) -> Peer {
         Peer(self.0)
     }
+    pub fn is_host(&self) -> bool {
+        self.host
+            .as_ref()
+            .is_some_and(|host| host.0 == self.me_res.0)
+    }
+    pub fn host(&self) -> Option<Peer> {
+        self.host.as_ref().map(|host| host.0)
+    }
 }
 
 impl PartialEq<Me<'_>> for Peer {
@@ -229,3 +492,63 @@ impl Reliability {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settle(registry: &mut PeerRegistry, current: &[Peer]) -> Vec<Peer> {
+        (0..10).flat_map(|_| registry.update(current).0).collect()
+    }
+
+    #[test]
+    fn peers_connect_after_a_few_frames() {
+        let mut registry = PeerRegistry::default();
+        let peer = Peer(1);
+        let (connected, _) = registry.update(&[peer]);
+        assert!(connected.is_empty());
+        assert_eq!(registry.status(&peer), Some(PeerStatus::Connecting(1)));
+        assert_eq!(settle(&mut registry, &[peer]), vec![peer]);
+        assert!(registry.is_connected(&peer));
+        assert!(registry.update(&[peer]).0.is_empty());
+    }
+
+    #[test]
+    fn peers_that_leave_can_rejoin() {
+        let mut registry = PeerRegistry::default();
+        let peer = Peer(1);
+        settle(&mut registry, &[peer]);
+        let (_, disconnected) = registry.update(&[]);
+        assert_eq!(disconnected, vec![(peer, DisconnectReason::Left)]);
+        assert_eq!(registry.status(&peer), None);
+        assert_eq!(settle(&mut registry, &[peer]), vec![peer]);
+    }
+
+    #[test]
+    fn severed_peers_stay_disconnected_until_readmitted() {
+        let mut registry = PeerRegistry::default();
+        let peer = Peer(1);
+        settle(&mut registry, &[peer]);
+        registry.disconnect(peer, DisconnectReason::Kicked);
+        let (_, disconnected) = registry.update(&[peer]);
+        assert_eq!(disconnected, vec![(peer, DisconnectReason::Kicked)]);
+        assert!(settle(&mut registry, &[peer]).is_empty());
+        assert_eq!(registry.kicked().collect::<Vec<_>>(), vec![peer]);
+        registry.readmit(peer);
+        assert_eq!(settle(&mut registry, &[peer]), vec![peer]);
+    }
+
+    #[test]
+    fn severing_a_connecting_peer_sends_nothing() {
+        let mut registry = PeerRegistry::default();
+        let peer = Peer(1);
+        registry.update(&[peer]);
+        registry.disconnect(peer, DisconnectReason::Timeout);
+        let (connected, disconnected) = registry.update(&[peer]);
+        assert!(connected.is_empty() && disconnected.is_empty());
+        assert_eq!(
+            registry.status(&peer),
+            Some(PeerStatus::Severed(DisconnectReason::Timeout))
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use crate::message_layer::outgoing::SenderRes;
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{
+    MeRes, NetworkedCommandExt, Peer, PeerConnected, PeerDisconnected, Reliability, connected,
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How long a peer may stay connected to the lobby owner without being admitted.
+const ADMISSION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a rejected peer gets to leave on its own before it is kicked.
+const REJECTION_GRACE: Duration = Duration::from_secs(1);
+
+/// Makes us the owner of the lobby, answering join requests from everyone else.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct LobbySettings {
+    pub max_players: Option<usize>,
+    pub password: Option<String>,
+    pub properties: HashMap<String, String>,
+}
+
+/// Makes us ask the lobby owner for admission once it announces itself.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct LobbyCredentials {
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LobbyState {
+    pub max_players: Option<usize>,
+    pub properties: HashMap<String, String>,
+    /// Every admitted player, including the owner, and whether they are ready.
+    pub ready: HashMap<Peer, bool>,
+}
+impl NetworkMessage for LobbyState {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// The lobby as last seen by us, kept up to date by the owner.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct Lobby {
+    owner: Option<Peer>,
+    /// The owner we sent our join request to.
+    asked: Option<Peer>,
+    state: LobbyState,
+}
+impl Lobby {
+    pub fn owner(&self) -> Option<Peer> {
+        self.owner
+    }
+    /// Whether `peer` runs the lobby, including an owner that has yet to answer our join request.
+    pub(crate) fn is_owner(&self, peer: Peer) -> bool {
+        self.owner == Some(peer) || self.asked == Some(peer)
+    }
+    pub fn state(&self) -> &LobbyState {
+        &self.state
+    }
+    pub fn properties(&self) -> &HashMap<String, String> {
+        &self.state.properties
+    }
+    pub fn players(&self) -> impl Iterator<Item = Peer> + '_ {
+        self.state.ready.keys().copied()
+    }
+    pub fn player_count(&self) -> usize {
+        self.state.ready.len()
+    }
+    pub fn is_ready(&self, peer: &Peer) -> bool {
+        self.state.ready.get(peer).copied().unwrap_or(false)
+    }
+    pub fn all_ready(&self) -> bool {
+        !self.state.ready.is_empty() && self.state.ready.values().all(|ready| *ready)
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum JoinRejected {
+    RoomFull,
+    WrongPassword,
+}
+
+#[derive(Event, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum JoinResult {
+    Accepted { owner: Peer },
+    Rejected(JoinRejected),
+}
+
+#[derive(Event, Copy, Clone, Debug)]
+pub struct AllReady;
+
+/// Sent by the owner to every peer that connects, so join requests only ever go to it.
+#[derive(Serialize, Deserialize)]
+pub struct LobbyOwner;
+impl NetworkMessage for LobbyOwner {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JoinRequest {
+    password: Option<String>,
+}
+impl NetworkMessage for JoinRequest {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum JoinResponse {
+    Accepted(LobbyState),
+    Rejected(JoinRejected),
+}
+impl NetworkMessage for JoinResponse {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetReady(bool);
+impl NetworkMessage for SetReady {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// When each peer the owner has not admitted gets kicked.
+#[derive(Resource, Default)]
+struct Admissions(HashMap<Peer, Duration>);
+
+pub trait LobbyCommandsExt {
+    fn create_lobby(&mut self, room_url: &str, settings: LobbySettings);
+    fn join_lobby(&mut self, room_url: &str, password: Option<String>);
+    fn set_ready(&mut self, ready: bool);
+}
+
+impl LobbyCommandsExt for Commands<'_, '_> {
+    fn create_lobby(&mut self, room_url: &str, settings: LobbySettings) {
+        self.insert_resource(Lobby::default());
+        self.insert_resource(settings);
+        self.connect(room_url);
+    }
+    fn join_lobby(&mut self, room_url: &str, password: Option<String>) {
+        self.insert_resource(Lobby::default());
+        self.insert_resource(LobbyCredentials { password });
+        self.connect(room_url);
+    }
+    fn set_ready(&mut self, ready: bool) {
+        self.queue(move |world: &mut World| {
+            let Some(me) = world.get_resource::<MeRes>().map(|me| me.0) else {
+                return;
+            };
+            let mut lobby = world.resource_mut::<Lobby>();
+            match lobby.owner {
+                Some(owner) if owner == me => {
+                    lobby.state.ready.insert(me, ready);
+                }
+                Some(owner) => {
+                    if let Err(err) = world
+                        .resource::<SenderRes<SetReady>>()
+                        .0
+                        .send((SetReady(ready), SendType::One(owner)))
+                    {
+                        error!("{}", err);
+                    }
+                }
+                None => warn!("not in a lobby"),
+            }
+        });
+    }
+}
+
+pub struct LobbyPlugin;
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Lobby>();
+        app.init_resource::<Admissions>();
+        app.add_event::<JoinResult>();
+        app.add_event::<AllReady>();
+        app.add_network_message(
+            |rx: MessageReceiver<LobbyOwner>,
+             credentials: Option<Res<LobbyCredentials>>,
+             mut lobby: ResMut<Lobby>,
+             sender: MessageSender<JoinRequest>| {
+                for (LobbyOwner, peer) in rx.try_iter() {
+                    let Some(credentials) = credentials.as_ref() else {
+                        continue;
+                    };
+                    if lobby.asked.is_some() {
+                        warn!(
+                            "{peer:?} claims to own the lobby, but we already asked another peer"
+                        );
+                        continue;
+                    }
+                    lobby.asked = Some(peer);
+                    let request = JoinRequest {
+                        password: credentials.password.clone(),
+                    };
+                    if let Err(err) = sender.send((request, SendType::One(peer))) {
+                        error!("{}", err);
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<JoinRequest>,
+             settings: Option<Res<LobbySettings>>,
+             mut lobby: ResMut<Lobby>,
+             mut admissions: ResMut<Admissions>,
+             time: Res<Time<Real>>,
+             sender: MessageSender<JoinResponse>| {
+                for (request, peer) in rx.try_iter() {
+                    let Some(settings) = settings.as_ref() else {
+                        continue;
+                    };
+                    if !admissions.0.contains_key(&peer) {
+                        // already admitted, or rejected and about to be kicked
+                        continue;
+                    }
+                    let response =
+                        if settings.password.is_some() && settings.password != request.password {
+                            JoinResponse::Rejected(JoinRejected::WrongPassword)
+                        } else if settings
+                            .max_players
+                            .is_some_and(|max| lobby.player_count() >= max)
+                        {
+                            JoinResponse::Rejected(JoinRejected::RoomFull)
+                        } else {
+                            lobby.state.ready.insert(peer, false);
+                            JoinResponse::Accepted(lobby.state.clone())
+                        };
+                    match response {
+                        JoinResponse::Accepted(_) => {
+                            admissions.0.remove(&peer);
+                        }
+                        JoinResponse::Rejected(_) => {
+                            admissions.0.insert(peer, time.elapsed() + REJECTION_GRACE);
+                        }
+                    }
+                    if let Err(err) = sender.send((response, SendType::One(peer))) {
+                        error!("{}", err);
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<JoinResponse>,
+             mut commands: Commands,
+             mut lobby: ResMut<Lobby>,
+             mut results: EventWriter<JoinResult>| {
+                for (response, peer) in rx.try_iter() {
+                    if lobby.asked != Some(peer) {
+                        continue;
+                    }
+                    match response {
+                        JoinResponse::Accepted(state) => {
+                            lobby.owner = Some(peer);
+                            lobby.state = state;
+                            results.send(JoinResult::Accepted { owner: peer });
+                        }
+                        JoinResponse::Rejected(reason) => {
+                            warn!("{peer:?} rejected us: {reason:?}");
+                            results.send(JoinResult::Rejected(reason));
+                            commands.disconnect();
+                        }
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<SetReady>, mut lobby: ResMut<Lobby>, me: Res<MeRes>| {
+                for (SetReady(ready), peer) in rx.try_iter() {
+                    if lobby.owner != Some(me.0) || !lobby.state.ready.contains_key(&peer) {
+                        continue;
+                    }
+                    lobby.state.ready.insert(peer, ready);
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<LobbyState>, mut lobby: ResMut<Lobby>| {
+                for (state, peer) in rx.try_iter() {
+                    if lobby.owner == Some(peer) {
+                        lobby.state = state;
+                    }
+                }
+            },
+        );
+        app.add_systems(
+            Update,
+            (own_lobby, admit_peers, broadcast_lobby_state)
+                .chain()
+                .run_if(connected.and(resource_exists::<LobbySettings>)),
+        );
+        app.add_systems(Update, detect_all_ready);
+    }
+}
+
+fn own_lobby(
+    settings: Res<LobbySettings>,
+    me: Res<MeRes>,
+    mut lobby: ResMut<Lobby>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+) {
+    if lobby.owner != Some(me.0) {
+        lobby.owner = Some(me.0);
+        lobby.state.ready.insert(me.0, false);
+    }
+    if settings.is_changed() {
+        lobby.state.max_players = settings.max_players;
+        lobby.state.properties = settings.properties.clone();
+    }
+    for peer_disconnected in peer_disconnected.read() {
+        if lobby.state.ready.contains_key(&peer_disconnected.get()) {
+            lobby.state.ready.remove(&peer_disconnected.get());
+        }
+    }
+}
+
+/// Announces us to new peers and kicks the ones that were rejected or never asked to join.
+fn admit_peers(
+    mut commands: Commands,
+    mut admissions: ResMut<Admissions>,
+    mut peer_connected: EventReader<PeerConnected>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    time: Res<Time<Real>>,
+    sender: MessageSender<LobbyOwner>,
+) {
+    let now = time.elapsed();
+    for peer_connected in peer_connected.read() {
+        admissions
+            .0
+            .insert(peer_connected.get(), now + ADMISSION_TIMEOUT);
+        if let Err(err) = sender.send((LobbyOwner, SendType::One(peer_connected.get()))) {
+            error!("{}", err);
+        }
+    }
+    for peer_disconnected in peer_disconnected.read() {
+        admissions.0.remove(&peer_disconnected.get());
+    }
+    admissions.0.retain(|peer, deadline| {
+        if *deadline > now {
+            return true;
+        }
+        warn!("kicking {peer:?}, who was not admitted to the lobby");
+        commands.kick(*peer);
+        false
+    });
+}
+
+fn broadcast_lobby_state(lobby: Res<Lobby>, me: Res<MeRes>, sender: MessageSender<LobbyState>) {
+    if !lobby.is_changed() {
+        return;
+    }
+    let players = lobby.players().filter(|peer| *peer != me.0).collect();
+    if let Err(err) = sender.send((lobby.state.clone(), SendType::Many(players))) {
+        error!("{}", err);
+    }
+}
+
+fn detect_all_ready(
+    lobby: Res<Lobby>,
+    mut was_ready: Local<bool>,
+    mut all_ready: EventWriter<AllReady>,
+) {
+    if !lobby.is_changed() {
+        return;
+    }
+    if lobby.all_ready() && !*was_ready {
+        all_ready.send(AllReady);
+    }
+    *was_ready = lobby.all_ready();
+}
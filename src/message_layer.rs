@@ -1,5 +1,9 @@
 use crate::message_layer::outgoing::SenderRes;
-use crate::{MeRes, Peer, RELIABLE, Reliability, UNRELIABLE, UNRELIABLE_ORDERED, connected};
+use crate::topology_layer::{HostAnnouncement, Topology};
+use crate::{
+    HostRes, MeRes, Peer, PeerRegistry, RELIABLE, Reliability, UNRELIABLE, UNRELIABLE_ORDERED,
+    connected,
+};
 use bevy::ecs::archetype::ArchetypeComponentId;
 use bevy::ecs::component::{ComponentId, Tick};
 use bevy::ecs::query::Access;
@@ -13,8 +17,10 @@ use serde::{Deserialize, Serialize};
 use std::any::type_name;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 // This is the base layer
 pub trait NetworkMessage: Serialize + for<'de> Deserialize<'de> + Send + Sync {
@@ -24,18 +30,25 @@ pub trait NetworkMessage: Serialize + for<'de> Deserialize<'de> + Send + Sync {
 #[derive(Serialize, Deserialize)]
 pub struct MessageWrapper {
     pub type_id_hash: u32,
+    pub relay: Option<Relay>,
     pub content: Vec<u8>,
 }
 impl MessageWrapper {
     pub fn _new<T: Serialize + 'static>(content: &T) -> Self {
         Self {
             type_id_hash: Self::hash::<T>(),
+            relay: None,
             content: bincode::serialize(content).unwrap(),
         }
     }
     pub fn serialize<T: Serialize + 'static>(content: &T) -> Vec<u8> {
         bincode::serialize(&Self::_new(content)).unwrap()
     }
+    pub fn serialize_relayed<T: Serialize + 'static>(content: &T, relay: Relay) -> Vec<u8> {
+        let mut wrapper = Self::_new(content);
+        wrapper.relay = Some(relay);
+        bincode::serialize(&wrapper).unwrap()
+    }
     pub fn hash<T: Serialize + 'static>() -> u32 {
         let mut hasher = DefaultHasher::new();
         type_name::<T>().hash(&mut hasher);
@@ -43,12 +56,106 @@ impl MessageWrapper {
     }
 }
 
+// Client-server mode: clients ask the host to forward `To`, the host forwards it as `From`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Relay {
+    To(Vec<Peer>),
+    From(Peer),
+}
+
 #[derive(Clone, Debug)]
 pub enum SendType {
     All,
     AllButSelf,
     Many(Vec<Peer>),
     One(Peer),
+    /// Evaluated against every connected peer and ourselves when the message is flushed.
+    Filter(PeerFilter),
+}
+
+impl SendType {
+    pub fn filter(f: impl Fn(Peer, Option<EntityRef>) -> bool + Send + Sync + 'static) -> Self {
+        SendType::Filter(PeerFilter::new(f))
+    }
+    /// Returns the remote peers targeted by this send and whether it also loops back to us.
+    pub fn resolve(
+        &self,
+        me: Peer,
+        peers: &[matchbox_socket::PeerId],
+        lookup: &PeerLookup,
+    ) -> (Vec<Peer>, bool) {
+        let remote = || peers.iter().map(|peer| Peer::from(*peer));
+        match self {
+            SendType::All => (remote().collect(), true),
+            SendType::AllButSelf => (remote().collect(), false),
+            SendType::Many(peers) => (peers.clone(), false),
+            SendType::One(peer) => (vec![*peer], false),
+            SendType::Filter(filter) => (
+                remote()
+                    .filter(|peer| lookup.matches(filter, *peer))
+                    .collect(),
+                lookup.matches(filter, me),
+            ),
+        }
+    }
+}
+
+/// A predicate over a peer and the entity carrying its [`Peer`] component, if there is one.
+#[derive(Clone)]
+pub struct PeerFilter(Arc<dyn Fn(Peer, Option<EntityRef>) -> bool + Send + Sync + 'static>);
+
+impl PeerFilter {
+    pub fn new(f: impl Fn(Peer, Option<EntityRef>) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+    /// Matches peers whose entity has a `C` for which `f` returns true.
+    pub fn with_component<C: Component>(f: impl Fn(&C) -> bool + Send + Sync + 'static) -> Self {
+        Self::new(move |_peer, entity| entity.and_then(|e| e.get::<C>()).is_some_and(&f))
+    }
+    /// Matches peers whose entity has a [`GlobalTransform`] within `radius` of `origin`.
+    pub fn within_distance(origin: Vec3, radius: f32) -> Self {
+        Self::with_component(move |transform: &GlobalTransform| {
+            transform.translation().distance_squared(origin) <= radius * radius
+        })
+    }
+    pub fn and(self, other: PeerFilter) -> Self {
+        Self::new(move |peer, entity| self.matches(peer, entity) && other.matches(peer, entity))
+    }
+    pub fn matches(&self, peer: Peer, entity: Option<EntityRef>) -> bool {
+        (self.0)(peer, entity)
+    }
+}
+
+impl Debug for PeerFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PeerFilter")
+    }
+}
+
+/// Resolves peers to their entities while outgoing messages are flushed.
+pub struct PeerLookup<'w> {
+    world: &'w World,
+    entities: HashMap<Peer, Entity>,
+}
+
+impl<'w> PeerLookup<'w> {
+    fn new(world: &'w mut World) -> Self {
+        let entities = world
+            .query::<(Entity, &Peer)>()
+            .iter(world)
+            .map(|(entity, peer)| (*peer, entity))
+            .collect();
+        Self { world, entities }
+    }
+    pub fn world(&self) -> &'w World {
+        self.world
+    }
+    pub fn entity(&self, peer: Peer) -> Option<EntityRef<'w>> {
+        self.world.get_entity(*self.entities.get(&peer)?).ok()
+    }
+    pub fn matches(&self, filter: &PeerFilter, peer: Peer) -> bool {
+        filter.matches(peer, self.entity(peer))
+    }
 }
 
 pub mod outgoing {
@@ -109,9 +216,19 @@ pub struct MessageRouter {
     pub route_incoming_messages: HashMap<u32, Box<dyn Fn(&[u8], Peer) + Send + Sync + 'static>>,
     pub route_outgoing_messages: Vec<
         Box<
-            dyn Fn(&mut MatchboxSocket, &MeRes, &[matchbox_socket::PeerId]) + Send + Sync + 'static,
+            dyn Fn(
+                    Option<&mut MatchboxSocket>,
+                    &MeRes,
+                    &[matchbox_socket::PeerId],
+                    &PeerLookup,
+                    Option<Peer>,
+                ) + Send
+                + Sync
+                + 'static,
         >,
     >,
+    pub validate_relayed_messages:
+        HashMap<u32, Box<dyn Fn(&[u8], Peer) -> bool + Send + Sync + 'static>>,
 }
 
 pub struct MessageLayerPlugin;
@@ -126,38 +243,140 @@ pub(crate) fn route_messages(world: &mut World) {
         return;
     };
     let me = *me;
-    let _ = world
-        .get_resource_mut::<MatchboxSocket>()
-        .unwrap()
-        .update_peers();
-    let peers = world
-        .get_resource_mut::<MatchboxSocket>()
-        .unwrap()
-        .connected_peers()
-        .collect::<Vec<_>>();
+    let topology = world
+        .get_resource::<Topology>()
+        .copied()
+        .unwrap_or_default();
+    let host = world.get_resource::<HostRes>().map(HostRes::get);
+    let relay_via = match topology {
+        Topology::Client => host.filter(|host| *host != me.0),
+        _ => None,
+    };
+    // clients hold on to their outgoing messages until they know who to relay them through
+    let hold_outgoing = topology == Topology::Client && host.is_none();
+    let severed = world
+        .get_resource::<PeerRegistry>()
+        .map(|registry| registry.severed().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let peers = match world.get_resource_mut::<MatchboxSocket>() {
+        Some(mut socket) => {
+            let _ = socket.update_peers();
+            socket.connected_peers().collect::<Vec<_>>()
+        }
+        // offline, everything we send only ever reaches ourselves
+        None => Vec::new(),
+    };
     world.resource_scope(|world, networked_messages: Mut<MessageRouter>| {
-        world.resource_scope(|_world, mut socket: Mut<MatchboxSocket>| {
-            for (peer_id, msg) in socket
-                .channel_mut(RELIABLE)
-                .receive()
-                .into_iter()
-                .chain(socket.channel_mut(UNRELIABLE).receive())
-                .chain(socket.channel_mut(UNRELIABLE_ORDERED).receive())
-                .map(|(peer_id, msg)| {
-                    (
-                        peer_id,
-                        bincode::deserialize::<MessageWrapper>(&msg).unwrap(),
-                    )
-                })
-            {
+        if !world.contains_resource::<MatchboxSocket>() {
+            let lookup = PeerLookup::new(world);
+            for route_outgoing_messages in &networked_messages.route_outgoing_messages {
+                route_outgoing_messages(None, &me, &peers, &lookup, None);
+            }
+            return;
+        }
+        world.resource_scope(|world, mut socket: Mut<MatchboxSocket>| {
+            let mut received = Vec::new();
+            for channel in [RELIABLE, UNRELIABLE, UNRELIABLE_ORDERED] {
+                for (peer_id, msg) in socket.channel_mut(channel).receive() {
+                    let msg = match bincode::deserialize::<MessageWrapper>(&msg) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            warn!("dropping malformed message from {peer_id:?}: {err}");
+                            continue;
+                        }
+                    };
+                    received.push((channel, Peer::from(peer_id), msg));
+                }
+            }
+            // the host's announcement can arrive in the same frame as its first messages
+            let host = host.or_else(|| {
+                received
+                    .iter()
+                    .find(|(_, _, msg)| {
+                        topology == Topology::Client
+                            && msg.relay.is_none()
+                            && msg.type_id_hash == MessageWrapper::hash::<HostAnnouncement>()
+                    })
+                    .map(|(_, sender, _)| *sender)
+            });
+            for (channel, sender, msg) in received {
+                if severed.contains(&sender) {
+                    continue;
+                }
+                // a dedicated host relays message types it never registered itself
                 let route_incoming_messages = networked_messages
                     .route_incoming_messages
-                    .get(&msg.type_id_hash)
-                    .unwrap();
-                route_incoming_messages(&msg.content, peer_id.into());
+                    .get(&msg.type_id_hash);
+                if route_incoming_messages.is_none() && !matches!(msg.relay, Some(Relay::To(_))) {
+                    warn!("dropping message of unregistered type {}", msg.type_id_hash);
+                    continue;
+                }
+                match msg.relay {
+                    None => {
+                        // clients only talk to each other through the host
+                        if topology == Topology::Client && host != Some(sender) {
+                            warn!("dropping message from {sender:?}, who is not the host");
+                            continue;
+                        }
+                        if host == Some(me.0)
+                            && networked_messages
+                                .validate_relayed_messages
+                                .get(&msg.type_id_hash)
+                                .is_some_and(|validate| !validate(&msg.content, sender))
+                        {
+                            warn!("message from {sender:?} failed validation");
+                            continue;
+                        }
+                        route_incoming_messages.unwrap()(&msg.content, sender);
+                    }
+                    Some(Relay::From(origin)) => {
+                        if host != Some(sender) {
+                            warn!("dropping message relayed by {sender:?}, who is not the host");
+                            continue;
+                        }
+                        route_incoming_messages.unwrap()(&msg.content, origin);
+                    }
+                    Some(Relay::To(targets)) => {
+                        if host != Some(me.0) {
+                            warn!("dropping relay request from {sender:?}, we are not the host");
+                            continue;
+                        }
+                        if networked_messages
+                            .validate_relayed_messages
+                            .get(&msg.type_id_hash)
+                            .is_some_and(|validate| !validate(&msg.content, sender))
+                        {
+                            warn!("relayed message from {sender:?} failed validation");
+                            continue;
+                        }
+                        let forwarded = bincode::serialize(&MessageWrapper {
+                            type_id_hash: msg.type_id_hash,
+                            relay: Some(Relay::From(sender)),
+                            content: msg.content.clone(),
+                        })
+                        .unwrap();
+                        for target in targets.iter().filter(|t| **t != me.0 && **t != sender) {
+                            if let Err(err) = socket
+                                .channel_mut(channel)
+                                .try_send(forwarded.clone().into(), (*target).into())
+                            {
+                                error!("{}", err);
+                            }
+                        }
+                        if let Some(route_incoming_messages) =
+                            route_incoming_messages.filter(|_| targets.contains(&me.0))
+                        {
+                            route_incoming_messages(&msg.content, sender);
+                        }
+                    }
+                }
+            }
+            if hold_outgoing {
+                return;
             }
+            let lookup = PeerLookup::new(world);
             for route_outgoing_messages in &networked_messages.route_outgoing_messages {
-                route_outgoing_messages(&mut socket, &me, &peers);
+                route_outgoing_messages(Some(&mut socket), &me, &peers, &lookup, relay_via);
             }
         });
     });
@@ -168,6 +387,11 @@ pub trait AppExt {
         &mut self,
         handler: impl IntoSystem<MessageReceiver<'static, Message>, (), M>,
     );
+    /// Lets the host reject client messages, both the ones it relays and the ones sent to it.
+    fn add_relay_validator<Message: NetworkMessage + 'static>(
+        &mut self,
+        validator: impl Fn(&Message, Peer) -> bool + Send + Sync + 'static,
+    ) -> &mut Self;
 }
 impl AppExt for App {
     fn add_network_message<Message: NetworkMessage + Sync + Send + 'static, M>(
@@ -188,63 +412,78 @@ impl AppExt for App {
             .route_incoming_messages
             .insert(
                 MessageWrapper::hash::<Message>(),
-                Box::new(move |bytes: &[u8], peer: Peer| {
-                    incoming_tx
-                        .send((bincode::deserialize(bytes).unwrap(), peer))
-                        .unwrap();
-                }),
+                Box::new(
+                    move |bytes: &[u8], peer: Peer| match bincode::deserialize(bytes) {
+                        Ok(message) => incoming_tx.send((message, peer)).unwrap(),
+                        Err(err) => warn!(
+                            "dropping malformed {} from {peer:?}: {err}",
+                            type_name::<Message>()
+                        ),
+                    },
+                ),
             );
         self.world_mut()
             .resource_mut::<MessageRouter>()
             .route_outgoing_messages
             .push(Box::new(
-                move |socket: &mut MatchboxSocket,
+                move |mut socket: Option<&mut MatchboxSocket>,
                       me: &MeRes,
-                      peers: &[matchbox_socket::PeerId]| {
-                    for (message, sender) in outgoing_rx.try_iter() {
-                        let channel = socket.channel_mut(Message::RELIABILITY as usize);
-                        let msg_bytes = MessageWrapper::serialize(&message);
-                        match sender {
-                            SendType::All => {
-                                for peer in peers {
-                                    if let Err(err) =
-                                        channel.try_send(msg_bytes.clone().into(), *peer)
-                                    {
-                                        error!("{}", err);
+                      peers: &[matchbox_socket::PeerId],
+                      lookup: &PeerLookup,
+                      relay_via: Option<Peer>| {
+                    for (message, send_type) in outgoing_rx.try_iter() {
+                        let (targets, to_self) = send_type.resolve(me.0, peers, lookup);
+                        if let Some(socket) = socket.as_deref_mut() {
+                            let channel = socket.channel_mut(Message::RELIABILITY as usize);
+                            match relay_via {
+                                Some(host) => {
+                                    if !targets.is_empty() {
+                                        let msg_bytes = MessageWrapper::serialize_relayed(
+                                            &message,
+                                            Relay::To(targets),
+                                        );
+                                        if let Err(err) =
+                                            channel.try_send(msg_bytes.into(), host.into())
+                                        {
+                                            error!("{}", err);
+                                        }
                                     }
                                 }
-                                incoming_tx_2.send((message, me.0)).unwrap()
-                            }
-                            SendType::AllButSelf => {
-                                for peer in peers {
-                                    if let Err(err) =
-                                        channel.try_send(msg_bytes.clone().into(), *peer)
-                                    {
-                                        error!("{}", err);
+                                None => {
+                                    let msg_bytes = MessageWrapper::serialize(&message);
+                                    for peer in targets {
+                                        if let Err(err) =
+                                            channel.try_send(msg_bytes.clone().into(), peer.into())
+                                        {
+                                            error!("{}", err);
+                                        }
                                     }
                                 }
                             }
-                            SendType::Many(peers) => {
-                                for peer in peers {
-                                    if let Err(err) =
-                                        channel.try_send(msg_bytes.clone().into(), peer.into())
-                                    {
-                                        error!("{}", err);
-                                    }
-                                }
-                            }
-                            SendType::One(peer) => {
-                                if let Err(err) =
-                                    channel.try_send(msg_bytes.clone().into(), peer.into())
-                                {
-                                    error!("{}", err);
-                                }
-                            }
+                        }
+                        if to_self {
+                            incoming_tx_2.send((message, me.0)).unwrap()
                         }
                     }
                 },
             ))
     }
+    fn add_relay_validator<Message: NetworkMessage + 'static>(
+        &mut self,
+        validator: impl Fn(&Message, Peer) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.init_resource::<MessageRouter>();
+        self.world_mut()
+            .resource_mut::<MessageRouter>()
+            .validate_relayed_messages
+            .insert(
+                MessageWrapper::hash::<Message>(),
+                Box::new(move |bytes: &[u8], peer: Peer| {
+                    bincode::deserialize(bytes).is_ok_and(|message| validator(&message, peer))
+                }),
+            );
+        self
+    }
 }
 
 struct LocalInputWrapper<S, T>(S, T);
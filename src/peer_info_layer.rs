@@ -0,0 +1,112 @@
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{Peer, PeerConnected, PeerDisconnected, Reliability, connected};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// Describes a peer to the rest of the room.
+///
+/// Insert it as a resource to describe ourselves. Remote peers' infos end up in [`PeerInfos`]
+/// and as a component on their [`Peer`] entities.
+#[derive(Component, Resource, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PeerInfo<T = ()> {
+    pub display_name: String,
+    pub payload: T,
+}
+
+impl<T: Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static> NetworkMessage
+    for PeerInfo<T>
+{
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Resource, Deref, DerefMut)]
+pub struct PeerInfos<T = ()>(pub HashMap<Peer, PeerInfo<T>>);
+impl<T> Default for PeerInfos<T> {
+    fn default() -> Self {
+        Self(HashMap::default())
+    }
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct PeerInfoChanged<T = ()> {
+    pub peer: Peer,
+    pub info: PeerInfo<T>,
+}
+
+pub struct PeerInfoPlugin<T = ()>(PhantomData<T>);
+impl<T> Default for PeerInfoPlugin<T> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static> Plugin
+    for PeerInfoPlugin<T>
+{
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PeerInfos<T>>();
+        app.add_event::<PeerInfoChanged<T>>();
+        app.add_network_message(
+            |rx: MessageReceiver<PeerInfo<T>>,
+             mut commands: Commands,
+             mut infos: ResMut<PeerInfos<T>>,
+             mut changed: EventWriter<PeerInfoChanged<T>>,
+             query: Query<(Entity, &Peer)>| {
+                for (info, peer) in rx.try_iter() {
+                    for (entity, other_peer) in query.iter() {
+                        if *other_peer == peer {
+                            commands.entity(entity).insert(info.clone());
+                        }
+                    }
+                    infos.insert(peer, info.clone());
+                    changed.send(PeerInfoChanged { peer, info });
+                }
+            },
+        );
+        app.add_systems(
+            Update,
+            send_peer_info::<T>.run_if(connected.and(resource_exists::<PeerInfo<T>>)),
+        );
+        app.add_systems(Update, (attach_peer_info::<T>, forget_peer_info::<T>));
+    }
+}
+
+fn send_peer_info<T: Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>(
+    info: Res<PeerInfo<T>>,
+    mut peer_connected: EventReader<PeerConnected>,
+    sender: MessageSender<PeerInfo<T>>,
+) {
+    let send_types = info
+        .is_changed()
+        .then_some(SendType::AllButSelf)
+        .into_iter()
+        .chain(peer_connected.read().map(|ev| SendType::One(ev.get())));
+    for send_type in send_types {
+        if let Err(err) = sender.send((info.clone(), send_type)) {
+            error!("{}", err);
+        }
+    }
+}
+
+fn attach_peer_info<T: Clone + Send + Sync + 'static>(
+    mut commands: Commands,
+    infos: Res<PeerInfos<T>>,
+    query: Query<(Entity, &Peer), Without<PeerInfo<T>>>,
+) {
+    for (entity, peer) in query.iter() {
+        if let Some(info) = infos.get(peer) {
+            commands.entity(entity).insert(info.clone());
+        }
+    }
+}
+
+fn forget_peer_info<T: Send + Sync + 'static>(
+    mut infos: ResMut<PeerInfos<T>>,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+) {
+    for peer_disconnected in peer_disconnected.read() {
+        infos.remove(&peer_disconnected.get());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::Reliability;
-use crate::component_sync_layer::{ComponentSyncPlugin, Four};
+use crate::component_sync_layer::ComponentSyncPlugin;
 use crate::message_layer::NetworkMessage;
 use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
 use serde::{Deserialize, Serialize};
@@ -11,4 +11,4 @@ impl NetworkMessage for Physics {
 }
 
 pub type PhysicsSyncPlugin =
-    ComponentSyncPlugin<(Position, Rotation, LinearVelocity, AngularVelocity), Physics, Four>;
+    ComponentSyncPlugin<(Position, Rotation, LinearVelocity, AngularVelocity), Physics>;
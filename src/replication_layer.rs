@@ -0,0 +1,459 @@
+use crate::component_sync_layer::{
+    Authority, DespawnOnDisconnect, LocalNet, NetworkEntityMapper, NetworkId, OnOwnerDisconnect,
+};
+use crate::interest_layer::{Interest, InterestGained, InterestLost, update_interest};
+use crate::message_layer::{
+    AppExt, MessageReceiver, MessageSender, MessageWrapper, NetworkMessage, SendType,
+};
+use crate::{Peer, PeerConnected, Reliability, connected};
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+type SerializeComponent = Box<dyn Fn(&EntityRef) -> Option<Vec<u8>> + Send + Sync>;
+type InsertComponent = Box<dyn Fn(&mut EntityCommands, &[u8]) + Send + Sync>;
+
+/// The components a mirror is spawned with, registered through [`ReplicationAppExt::replicate`].
+#[derive(Resource, Default)]
+pub struct ReplicationRegistry {
+    serialize: Vec<(u32, SerializeComponent)>,
+    insert: HashMap<u32, InsertComponent>,
+}
+
+/// Announces a networked entity so peers can spawn a mirror of it.
+#[derive(Serialize, Deserialize)]
+pub struct SpawnNetworked {
+    network_id: NetworkId,
+    authority: Authority,
+    parent: Option<NetworkId>,
+    on_owner_disconnect: Option<OnOwnerDisconnect>,
+    components: Vec<(u32, Vec<u8>)>,
+}
+impl NetworkMessage for SpawnNetworked {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DespawnNetworked(NetworkId);
+impl NetworkMessage for DespawnNetworked {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// Moves a networked entity under another one, or out of its parent with `None`.
+#[derive(Serialize, Deserialize)]
+pub struct Reparent {
+    child: NetworkId,
+    parent: Option<NetworkId>,
+}
+impl NetworkMessage for Reparent {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// Mirrors spawned this frame, which the [`NetworkEntityMapper`] only learns about once commands
+/// are applied.
+#[derive(Resource, Default)]
+struct SpawnedMirrors(HashSet<NetworkId>);
+
+/// Parents of mirrors that are applied once both ends have been spawned.
+#[derive(Resource, Default)]
+struct PendingParents(HashMap<NetworkId, Option<NetworkId>>);
+
+/// Part of the entities a peer owns, sent to peers that just connected.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    entities: Vec<SpawnNetworked>,
+    last: bool,
+}
+impl NetworkMessage for SnapshotChunk {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+/// `peer` has sent us every entity it owns, they are spawned by the time this is read.
+#[derive(Event, Copy, Clone, Debug)]
+pub struct SnapshotComplete {
+    pub peer: Peer,
+}
+
+/// Serialized component bytes after which a snapshot is split into another chunk.
+const SNAPSHOT_CHUNK_SIZE: usize = 16 * 1024;
+
+pub trait ReplicationAppExt {
+    /// Includes `C` in the mirrors spawned by [`ReplicationPlugin`], which
+    /// [`ComponentSyncPlugin`] already does for its bundle.
+    ///
+    /// [`ComponentSyncPlugin`]: crate::component_sync_layer::ComponentSyncPlugin
+    fn replicate<C: Component + Serialize + for<'de> Deserialize<'de>>(&mut self) -> &mut Self;
+}
+impl ReplicationAppExt for App {
+    fn replicate<C: Component + Serialize + for<'de> Deserialize<'de>>(&mut self) -> &mut Self {
+        let mut registry = self
+            .world_mut()
+            .get_resource_or_insert_with(ReplicationRegistry::default);
+        let hash = MessageWrapper::hash::<C>();
+        // a component synced by more than one plugin is sent once
+        if registry.insert.contains_key(&hash) {
+            return self;
+        }
+        registry.serialize.push((
+            hash,
+            Box::new(|entity: &EntityRef| {
+                let component = entity.get::<C>()?;
+                bincode::serialize(component)
+                    .inspect_err(|err| error!("{}", err))
+                    .ok()
+            }),
+        ));
+        registry.insert.insert(
+            hash,
+            Box::new(|entity: &mut EntityCommands, bytes: &[u8]| {
+                match bincode::deserialize::<C>(bytes) {
+                    Ok(component) => {
+                        entity.insert(component);
+                    }
+                    Err(err) => error!("{}", err),
+                }
+            }),
+        );
+        self
+    }
+}
+
+/// Entities we give a [`NetworkId`] and [`LocalNet`] are spawned on every peer, and despawned
+/// everywhere once we despawn them.
+pub struct ReplicationPlugin;
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationRegistry>();
+        app.init_resource::<NetworkEntityMapper>();
+        app.init_resource::<PendingParents>();
+        app.init_resource::<SpawnedMirrors>();
+        app.add_event::<SnapshotComplete>();
+        app.add_network_message(
+            |rx: MessageReceiver<SpawnNetworked>,
+             mut commands: Commands,
+             entity_mapper: Res<NetworkEntityMapper>,
+             registry: Res<ReplicationRegistry>,
+             mut pending_parents: ResMut<PendingParents>,
+             mut spawned: ResMut<SpawnedMirrors>| {
+                for (spawn, peer) in rx.try_iter() {
+                    spawn_mirror(
+                        &mut commands,
+                        &entity_mapper,
+                        &registry,
+                        &mut pending_parents,
+                        &mut spawned,
+                        spawn,
+                        peer,
+                    );
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<SnapshotChunk>,
+             mut commands: Commands,
+             entity_mapper: Res<NetworkEntityMapper>,
+             registry: Res<ReplicationRegistry>,
+             mut pending_parents: ResMut<PendingParents>,
+             mut spawned: ResMut<SpawnedMirrors>,
+             mut snapshot_complete: EventWriter<SnapshotComplete>| {
+                for (chunk, peer) in rx.try_iter() {
+                    for spawn in chunk.entities {
+                        spawn_mirror(
+                            &mut commands,
+                            &entity_mapper,
+                            &registry,
+                            &mut pending_parents,
+                            &mut spawned,
+                            spawn,
+                            peer,
+                        );
+                    }
+                    if chunk.last {
+                        snapshot_complete.send(SnapshotComplete { peer });
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<DespawnNetworked>,
+             mut commands: Commands,
+             entity_mapper: Res<NetworkEntityMapper>,
+             mirrors: Query<(), Without<LocalNet>>| {
+                for (DespawnNetworked(network_id), _peer) in rx.try_iter() {
+                    let Some(e) = entity_mapper.get(&network_id) else {
+                        continue;
+                    };
+                    if mirrors.contains(*e) {
+                        commands.entity(*e).despawn_recursive();
+                    }
+                }
+            },
+        );
+        app.add_network_message(
+            |rx: MessageReceiver<Reparent>,
+             entity_mapper: Res<NetworkEntityMapper>,
+             mut pending_parents: ResMut<PendingParents>| {
+                for (Reparent { child, parent }, _peer) in rx.try_iter() {
+                    if entity_mapper.contains_key(&child) {
+                        pending_parents.0.insert(child, parent);
+                    }
+                }
+            },
+        );
+        app.add_systems(
+            PostUpdate,
+            (announce_spawns, send_reparents)
+                .chain()
+                .after(update_interest)
+                .run_if(connected),
+        );
+        app.add_systems(
+            PostUpdate,
+            follow_interest
+                .after(update_interest)
+                .run_if(connected.and(resource_exists::<Interest>)),
+        );
+        app.add_systems(
+            PostUpdate,
+            (resolve_parents, |mut spawned: ResMut<SpawnedMirrors>| {
+                spawned.0.clear()
+            }),
+        );
+        app.add_systems(Update, send_snapshots.run_if(connected));
+        app.add_observer(
+            |trigger: Trigger<OnRemove, NetworkId>,
+             owned: Query<&NetworkId, With<LocalNet>>,
+             interest: Option<Res<Interest>>,
+             sender: MessageSender<DespawnNetworked>| {
+                let Ok(network_id) = owned.get(trigger.entity()) else {
+                    return;
+                };
+                let send_type = interest.map_or(SendType::AllButSelf, |interest| {
+                    interest.send_type(trigger.entity())
+                });
+                if let Err(err) = sender.send((DespawnNetworked(*network_id), send_type)) {
+                    error!("{}", err);
+                }
+            },
+        );
+    }
+}
+
+fn spawn_mirror(
+    commands: &mut Commands,
+    entity_mapper: &NetworkEntityMapper,
+    registry: &ReplicationRegistry,
+    pending_parents: &mut PendingParents,
+    spawned: &mut SpawnedMirrors,
+    spawn: SpawnNetworked,
+    peer: Peer,
+) {
+    // a snapshot and an announcement can both carry an entity spawned as the peer connected
+    if entity_mapper.contains_key(&spawn.network_id) || !spawned.0.insert(spawn.network_id) {
+        return;
+    }
+    if let Some(parent) = spawn.parent {
+        pending_parents.0.insert(spawn.network_id, Some(parent));
+    }
+    let mut entity = commands.spawn((spawn.network_id, spawn.authority, DespawnOnDisconnect(peer)));
+    if let Some(on_owner_disconnect) = spawn.on_owner_disconnect {
+        entity.insert(on_owner_disconnect);
+    }
+    for (hash, bytes) in &spawn.components {
+        match registry.insert.get(hash) {
+            Some(insert) => insert(&mut entity, bytes),
+            None => warn!("{peer:?} replicated an unregistered component {hash}"),
+        }
+    }
+}
+
+fn describe(
+    registry: &ReplicationRegistry,
+    network_ids: &Query<&NetworkId>,
+    entity: &EntityRef,
+    network_id: NetworkId,
+    authority: Authority,
+) -> SpawnNetworked {
+    SpawnNetworked {
+        network_id,
+        authority,
+        parent: entity
+            .get::<Parent>()
+            .and_then(|parent| network_ids.get(parent.get()).ok())
+            .copied(),
+        on_owner_disconnect: entity.get::<OnOwnerDisconnect>().copied(),
+        components: registry
+            .serialize
+            .iter()
+            .filter_map(|(hash, serialize)| Some((*hash, serialize(entity)?)))
+            .collect(),
+    }
+}
+
+fn announce_spawns(
+    registry: Res<ReplicationRegistry>,
+    interest: Option<Res<Interest>>,
+    sender: MessageSender<SpawnNetworked>,
+    query: Query<(EntityRef, &NetworkId, &Authority), Added<LocalNet>>,
+    network_ids: Query<&NetworkId>,
+) {
+    for (entity, network_id, authority) in query.iter() {
+        // spawned as it becomes relevant instead
+        if interest
+            .as_ref()
+            .is_some_and(|interest| interest.tracks(entity.id()))
+        {
+            continue;
+        }
+        if let Err(err) = sender.send((
+            describe(&registry, &network_ids, &entity, *network_id, *authority),
+            SendType::AllButSelf,
+        )) {
+            error!("{}", err);
+        }
+    }
+}
+
+/// Spawns our entities on peers that start seeing them, and despawns them on peers that stop.
+fn follow_interest(
+    registry: Res<ReplicationRegistry>,
+    mut gained: EventReader<InterestGained>,
+    mut lost: EventReader<InterestLost>,
+    spawn_sender: MessageSender<SpawnNetworked>,
+    despawn_sender: MessageSender<DespawnNetworked>,
+    query: Query<(EntityRef, &NetworkId, &Authority), With<LocalNet>>,
+    network_ids: Query<&NetworkId>,
+) {
+    for InterestGained { entity, peer } in gained.read() {
+        let Ok((entity, network_id, authority)) = query.get(*entity) else {
+            continue;
+        };
+        if let Err(err) = spawn_sender.send((
+            describe(&registry, &network_ids, &entity, *network_id, *authority),
+            SendType::One(*peer),
+        )) {
+            error!("{}", err);
+        }
+    }
+    for InterestLost { entity, peer } in lost.read() {
+        let Ok((_, network_id, _)) = query.get(*entity) else {
+            continue;
+        };
+        if let Err(err) = despawn_sender.send((DespawnNetworked(*network_id), SendType::One(*peer)))
+        {
+            error!("{}", err);
+        }
+    }
+}
+
+fn send_snapshots(
+    registry: Res<ReplicationRegistry>,
+    interest: Option<Res<Interest>>,
+    mut peer_connected: EventReader<PeerConnected>,
+    sender: MessageSender<SnapshotChunk>,
+    query: Query<(EntityRef, &NetworkId, &Authority), With<LocalNet>>,
+    network_ids: Query<&NetworkId>,
+) {
+    for peer_connected in peer_connected.read() {
+        let mut entities = Vec::new();
+        let mut size = 0;
+        let send = |entities: Vec<SpawnNetworked>, last: bool| {
+            if let Err(err) = sender.send((
+                SnapshotChunk { entities, last },
+                SendType::One(peer_connected.get()),
+            )) {
+                error!("{}", err);
+            }
+        };
+        for (entity, network_id, authority) in query.iter() {
+            if interest
+                .as_ref()
+                .is_some_and(|interest| interest.tracks(entity.id()))
+            {
+                continue;
+            }
+            let spawn = describe(&registry, &network_ids, &entity, *network_id, *authority);
+            size += spawn
+                .components
+                .iter()
+                .map(|(_, bytes)| bytes.len())
+                .sum::<usize>();
+            entities.push(spawn);
+            if size >= SNAPSHOT_CHUNK_SIZE {
+                send(std::mem::take(&mut entities), false);
+                size = 0;
+            }
+        }
+        // sent even when empty, the peer is waiting on it
+        send(entities, true);
+    }
+}
+
+fn send_reparents(
+    sender: MessageSender<Reparent>,
+    interest: Option<Res<Interest>>,
+    mut orphaned: RemovedComponents<Parent>,
+    reparented: Query<(Entity, &NetworkId, &Parent), (Changed<Parent>, With<LocalNet>)>,
+    owned: Query<&NetworkId, (With<LocalNet>, Without<Parent>)>,
+    network_ids: Query<&NetworkId>,
+) {
+    let reparents = reparented
+        .iter()
+        .map(|(e, child, parent)| {
+            (
+                e,
+                Reparent {
+                    child: *child,
+                    parent: network_ids.get(parent.get()).ok().copied(),
+                },
+            )
+        })
+        .chain(orphaned.read().filter_map(|e| {
+            Some((
+                e,
+                Reparent {
+                    child: *owned.get(e).ok()?,
+                    parent: None,
+                },
+            ))
+        }));
+    for (e, reparent) in reparents {
+        let send_type = interest
+            .as_ref()
+            .map_or(SendType::AllButSelf, |interest| interest.send_type(e));
+        if let Err(err) = sender.send((reparent, send_type)) {
+            error!("{}", err);
+        }
+    }
+}
+
+fn resolve_parents(
+    mut commands: Commands,
+    mut pending_parents: ResMut<PendingParents>,
+    entity_mapper: Res<NetworkEntityMapper>,
+    mirrors: Query<(), Without<LocalNet>>,
+) {
+    pending_parents.0.retain(|child, parent| {
+        // the child is gone, or was never spawned here
+        let Some(child) = entity_mapper
+            .get(child)
+            .filter(|child| mirrors.contains(**child))
+        else {
+            return false;
+        };
+        match parent {
+            None => {
+                commands.entity(*child).remove_parent();
+                false
+            }
+            Some(parent) => {
+                let Some(parent) = entity_mapper.get(parent) else {
+                    return true;
+                };
+                commands.entity(*child).set_parent(*parent);
+                false
+            }
+        }
+    });
+}
@@ -0,0 +1,181 @@
+use crate::component_sync_layer::successor;
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::topology_layer::{HostChanged, migrate_host};
+use crate::{
+    HostRes, MeRes, Peer, PeerConnected, PeerDisconnected, PeerRegistry, Reliability, connected,
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// Which peer may change the synced resource `R`, only its changes are sent.
+///
+/// Moves to the next host, or the lowest connected peer, when the owner disconnects.
+#[derive(Resource)]
+pub struct ResourceAuthority<R> {
+    counter: u32,
+    owner: Option<Peer>,
+    phantom_data: PhantomData<R>,
+}
+impl<R> Default for ResourceAuthority<R> {
+    fn default() -> Self {
+        Self {
+            counter: 0,
+            owner: None,
+            phantom_data: PhantomData,
+        }
+    }
+}
+impl<R> ResourceAuthority<R> {
+    pub fn owner(&self) -> Option<Peer> {
+        self.owner
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResourceSyncMsg<ReliabilityImplementor, R> {
+    counter: u32,
+    owner: Option<Peer>,
+    data: R,
+    phantom_data: PhantomData<ReliabilityImplementor>,
+}
+impl<
+    ReliabilityImplementor: NetworkMessage,
+    R: Send + Sync + Serialize + for<'de> Deserialize<'de> + 'static,
+> NetworkMessage for ResourceSyncMsg<ReliabilityImplementor, R>
+{
+    const RELIABILITY: Reliability = ReliabilityImplementor::RELIABILITY;
+}
+
+pub trait ResourceSyncCommandsExt {
+    /// Makes us the owner of `R`, peers adopt our value from then on.
+    fn take_resource_authority<R: Resource>(&mut self);
+}
+impl ResourceSyncCommandsExt for Commands<'_, '_> {
+    fn take_resource_authority<R: Resource>(&mut self) {
+        self.queue(|world: &mut World| {
+            let Some(me) = world.get_resource::<MeRes>().map(|me| me.0) else {
+                warn!("can't take authority over a resource before connecting");
+                return;
+            };
+            let Some(mut authority) = world.get_resource_mut::<ResourceAuthority<R>>() else {
+                error!("{} is not synced", std::any::type_name::<R>());
+                return;
+            };
+            authority.counter += 1;
+            authority.owner = Some(me);
+        });
+    }
+}
+
+/// Syncs the resource `R` from its [`ResourceAuthority`] owner to everyone else, including late joiners.
+pub struct ResourceSyncPlugin<R, ReliabilityImplementor>(PhantomData<(R, ReliabilityImplementor)>);
+impl<R, ReliabilityImplementor> Default for ResourceSyncPlugin<R, ReliabilityImplementor> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+unsafe impl<R, ReliabilityImplementor> Send for ResourceSyncPlugin<R, ReliabilityImplementor> {}
+unsafe impl<R, ReliabilityImplementor> Sync for ResourceSyncPlugin<R, ReliabilityImplementor> {}
+
+impl<
+    R: Resource + Clone + Serialize + for<'de> Deserialize<'de>,
+    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
+> Plugin for ResourceSyncPlugin<R, ReliabilityImplementor>
+{
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ResourceAuthority<R>>();
+        app.add_network_message(
+            |rx: MessageReceiver<ResourceSyncMsg<ReliabilityImplementor, R>>,
+             mut commands: Commands,
+             mut authority: ResMut<ResourceAuthority<R>>,
+             mut resource: Option<ResMut<R>>| {
+                for (msg, _peer) in rx.try_iter() {
+                    // a concurrent takeover is won by the higher peer
+                    if (msg.counter, msg.owner) < (authority.counter, authority.owner) {
+                        continue;
+                    }
+                    authority.counter = msg.counter;
+                    authority.owner = msg.owner;
+                    match resource.as_mut() {
+                        Some(resource) => **resource = msg.data,
+                        None => commands.insert_resource(msg.data),
+                    }
+                }
+            },
+        );
+        app.add_systems(
+            PreUpdate,
+            transfer_resource_authority::<R>.after(migrate_host),
+        );
+        app.add_systems(
+            PostUpdate,
+            send_resource::<R, ReliabilityImplementor>.run_if(connected.and(resource_exists::<R>)),
+        );
+    }
+}
+
+fn transfer_resource_authority<R: Resource>(
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    mut host_changed: EventReader<HostChanged>,
+    me: Option<Res<MeRes>>,
+    host: Option<Res<HostRes>>,
+    registry: Res<PeerRegistry>,
+    mut authority: ResMut<ResourceAuthority<R>>,
+) {
+    let adopted = host_changed
+        .read()
+        .filter_map(|ev| Some((ev.previous?, ev.new)))
+        .collect::<HashMap<_, _>>();
+    let Some(me) = me.map(|me| me.0) else {
+        return;
+    };
+    for peer_disconnected in peer_disconnected.read() {
+        let peer = peer_disconnected.get();
+        if authority.owner != Some(peer) {
+            continue;
+        }
+        authority.counter += 1;
+        authority.owner = Some(successor(
+            peer,
+            adopted.get(&peer).copied(),
+            host.as_deref(),
+            &registry,
+            me,
+        ));
+    }
+}
+
+fn send_resource<
+    R: Resource + Clone + Serialize + for<'de> Deserialize<'de>,
+    ReliabilityImplementor: NetworkMessage + Send + Sync + 'static,
+>(
+    me: Res<MeRes>,
+    resource: Res<R>,
+    authority: Res<ResourceAuthority<R>>,
+    mut peer_connected: EventReader<PeerConnected>,
+    sender: MessageSender<ResourceSyncMsg<ReliabilityImplementor, R>>,
+) {
+    if authority.owner != Some(me.0) {
+        peer_connected.clear();
+        return;
+    }
+    let send_types = (resource.is_changed() || authority.is_changed())
+        .then_some(SendType::AllButSelf)
+        .into_iter()
+        .chain(peer_connected.read().map(|ev| SendType::One(ev.get())));
+    for send_type in send_types {
+        if let Err(err) = sender.send((
+            ResourceSyncMsg {
+                counter: authority.counter,
+                owner: authority.owner,
+                data: resource.clone(),
+                phantom_data: PhantomData,
+            },
+            send_type,
+        )) {
+            error!("{}", err);
+        }
+    }
+}
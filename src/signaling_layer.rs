@@ -0,0 +1,48 @@
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_signaling::SignalingServer;
+use bevy_matchbox::prelude::MatchboxServer;
+use std::net::{Ipv4Addr, SocketAddr};
+
+pub const DEFAULT_SIGNALING_PORT: u16 = 3536;
+
+/// Hosts a full mesh matchbox signaling server inside the app, for LAN play and local testing.
+pub struct SignalingServerPlugin {
+    pub address: SocketAddr,
+}
+impl Default for SignalingServerPlugin {
+    fn default() -> Self {
+        Self {
+            address: (Ipv4Addr::UNSPECIFIED, DEFAULT_SIGNALING_PORT).into(),
+        }
+    }
+}
+
+impl Plugin for SignalingServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SignalingServerAddress(self.address));
+        app.add_systems(Startup, start_signaling_server);
+    }
+}
+
+#[derive(Resource, Copy, Clone, Debug)]
+pub struct SignalingServerAddress(pub SocketAddr);
+impl SignalingServerAddress {
+    /// The url peers on this machine pass to `connect` to join `room` on this server.
+    pub fn room_url(&self, room: &str) -> String {
+        let mut address = self.0;
+        if address.ip().is_unspecified() {
+            address.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        format!("ws://{address}/{room}")
+    }
+}
+
+fn start_signaling_server(mut commands: Commands, address: Res<SignalingServerAddress>) {
+    info!("starting signaling server on {}", address.0);
+    let server = SignalingServer::full_mesh_builder(address.0)
+        .on_peer_connected(|id| info!("signaling: {id:?} joined"))
+        .on_peer_disconnected(|id| info!("signaling: {id:?} left"))
+        .cors()
+        .trace();
+    commands.insert_resource(MatchboxServer::from(server));
+}
@@ -0,0 +1,136 @@
+use crate::message_layer::{AppExt, MessageReceiver, MessageSender, NetworkMessage, SendType};
+use crate::{
+    HostRes, Me, MeRes, Peer, PeerConnected, PeerDisconnected, PeerRegistry, Reliability,
+    connected, update_peers,
+};
+use bevy::prelude::*;
+use bevy_matchbox::MatchboxSocket;
+use serde::{Deserialize, Serialize};
+
+#[derive(Resource, Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Topology {
+    /// Every peer sends directly to every other peer.
+    #[default]
+    Mesh,
+    /// We are the authoritative peer and relay messages for the clients.
+    Host,
+    /// We only send to the host, which relays or rejects our messages.
+    Client,
+}
+
+#[derive(Event, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct HostChanged {
+    pub previous: Option<Peer>,
+    pub new: Peer,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HostAnnouncement;
+impl NetworkMessage for HostAnnouncement {
+    const RELIABILITY: Reliability = Reliability::Reliable;
+}
+
+pub struct TopologyPlugin;
+impl Plugin for TopologyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Topology>();
+        app.add_event::<HostChanged>();
+        app.add_network_message(
+            |rx: MessageReceiver<HostAnnouncement>,
+             mut commands: Commands,
+             mut host_changed: EventWriter<HostChanged>,
+             topology: Res<Topology>,
+             host: Option<Res<HostRes>>| {
+                for (_, peer) in rx.try_iter() {
+                    if *topology != Topology::Client {
+                        warn!("{peer:?} announced itself as host, but we are not a client");
+                        continue;
+                    }
+                    let previous = host.as_ref().map(|host| host.0);
+                    if previous != Some(peer) {
+                        commands.insert_resource(HostRes(peer));
+                        host_changed.send(HostChanged {
+                            previous,
+                            new: peer,
+                        });
+                    }
+                }
+            },
+        );
+        app.add_systems(
+            Update,
+            (claim_host, announce_host).chain().run_if(connected),
+        );
+        app.add_systems(
+            PreUpdate,
+            migrate_host
+                .after(update_peers)
+                .run_if(connected.and(resource_exists::<MatchboxSocket>)),
+        );
+    }
+}
+
+fn claim_host(
+    mut commands: Commands,
+    mut host_changed: EventWriter<HostChanged>,
+    topology: Res<Topology>,
+    me: Res<MeRes>,
+    host: Option<Res<HostRes>>,
+) {
+    if *topology == Topology::Host && host.as_ref().is_none_or(|host| host.0 != me.0) {
+        commands.insert_resource(HostRes(me.0));
+        host_changed.send(HostChanged {
+            previous: host.map(|host| host.0),
+            new: me.0,
+        });
+    }
+}
+
+/// Elects the lowest remaining connected [`Peer`] as the new host when the current one disconnects.
+pub(crate) fn migrate_host(
+    mut commands: Commands,
+    mut peer_disconnected: EventReader<PeerDisconnected>,
+    mut host_changed: EventWriter<HostChanged>,
+    topology: Res<Topology>,
+    host: Option<Res<HostRes>>,
+    me: Res<MeRes>,
+    registry: Res<PeerRegistry>,
+) {
+    for peer_disconnected in peer_disconnected.read() {
+        let previous = peer_disconnected.get();
+        if *topology == Topology::Mesh || host.as_ref().is_none_or(|host| host.0 != previous) {
+            continue;
+        }
+        // peers still connecting or already cut off don't get a say
+        let new_host = registry
+            .connected()
+            .filter(|peer| *peer != previous)
+            .chain([me.0])
+            .min()
+            .unwrap();
+        if new_host == me.0 {
+            info!("host {previous:?} left, taking over as host");
+            commands.insert_resource(Topology::Host);
+        }
+        commands.insert_resource(HostRes(new_host));
+        host_changed.send(HostChanged {
+            previous: Some(previous),
+            new: new_host,
+        });
+    }
+}
+
+fn announce_host(
+    me: Me,
+    mut peer_connected: EventReader<PeerConnected>,
+    sender: MessageSender<HostAnnouncement>,
+) {
+    for peer_connected in peer_connected.read() {
+        if !me.is_host() {
+            continue;
+        }
+        if let Err(err) = sender.send((HostAnnouncement, SendType::One(peer_connected.get()))) {
+            error!("{}", err);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use evnet::delta_sync_layer::{Delta, Diff};
+use evnet_macros::Diff;
+
+#[derive(Diff, Clone, Debug, Default, PartialEq)]
+struct Player {
+    position: (f32, f32),
+    health: u32,
+    name: String,
+}
+
+#[derive(Diff, Clone, Debug, Default, PartialEq)]
+struct Pair(u8, Vec<u8>);
+
+fn roundtrip(delta: &Delta) -> Delta {
+    bincode::deserialize(&bincode::serialize(delta).unwrap()).unwrap()
+}
+
+fn player() -> Player {
+    Player {
+        position: (1.0, 2.0),
+        health: 100,
+        name: "alice".to_string(),
+    }
+}
+
+#[test]
+fn full_diff_restores_every_field() {
+    let player = player();
+    let mut mirror = Player::default();
+    mirror.apply_diff(&roundtrip(&player.diff(None))).unwrap();
+    assert_eq!(mirror, player);
+}
+
+#[test]
+fn diff_only_carries_changed_fields() {
+    let baseline = player();
+    let current = Player {
+        health: 90,
+        ..baseline.clone()
+    };
+    let delta = roundtrip(&current.diff(Some(&baseline)));
+    let mut mirror = baseline.clone();
+    mirror.apply_diff(&delta).unwrap();
+    assert_eq!(mirror, current);
+    // fields that didn't change are left alone
+    let mut other = Player::default();
+    other.apply_diff(&delta).unwrap();
+    assert_eq!(
+        other,
+        Player {
+            health: 90,
+            ..Player::default()
+        }
+    );
+}
+
+#[test]
+fn unchanged_state_diffs_to_nothing() {
+    let player = player();
+    assert!(player.diff(Some(&player)).is_empty());
+}
+
+#[test]
+fn tuple_structs_diff_by_position() {
+    let baseline = Pair(1, vec![1, 2]);
+    let current = Pair(1, vec![3]);
+    let mut mirror = baseline.clone();
+    mirror
+        .apply_diff(&roundtrip(&current.diff(Some(&baseline))))
+        .unwrap();
+    assert_eq!(mirror, current);
+}
+
+#[test]
+fn truncated_deltas_fail_to_apply() {
+    let full = bincode::serialize(&player().diff(None)).unwrap();
+    let (mask, bytes): (u64, Vec<u8>) = bincode::deserialize(&full).unwrap();
+    let truncated: Delta =
+        bincode::deserialize(&bincode::serialize(&(mask, &bytes[..bytes.len() - 1])).unwrap())
+            .unwrap();
+    assert!(Player::default().apply_diff(&truncated).is_err());
+}
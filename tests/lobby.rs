@@ -0,0 +1,90 @@
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use evnet::NetworkingPlugins;
+use evnet::lobby_layer::{JoinRejected, JoinResult, LobbyCommandsExt, LobbySettings};
+use evnet::signaling_layer::{SignalingServerAddress, SignalingServerPlugin};
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::{Duration, Instant};
+
+// Runs a local signaling server, a lobby owner and two joiners in one process, no internet needed:
+// cargo test --test lobby --no-default-features --features signaling
+#[test]
+fn lobby_admits_only_the_right_password() {
+    // any free port, so this doesn't clash with a signaling server already running
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = SignalingServerAddress((Ipv4Addr::LOCALHOST, port).into());
+    let room_url = address.room_url("lobby");
+
+    let mut server = App::new();
+    server.add_plugins((
+        MinimalPlugins,
+        LogPlugin::default(),
+        SignalingServerPlugin { address: address.0 },
+    ));
+    server.finish();
+    server.cleanup();
+
+    let owner_url = room_url.clone();
+    let mut owner = peer(move |mut commands: Commands| {
+        commands.create_lobby(
+            &owner_url,
+            LobbySettings {
+                max_players: Some(2),
+                password: Some("hunter2".to_string()),
+                ..default()
+            },
+        );
+    });
+    let joiner_url = room_url.clone();
+    let mut joiner = peer(move |mut commands: Commands| {
+        commands.join_lobby(&joiner_url, Some("hunter2".to_string()));
+    });
+    let intruder_url = room_url;
+    let mut intruder = peer(move |mut commands: Commands| {
+        commands.join_lobby(&intruder_url, Some("letmein".to_string()));
+    });
+
+    let start = Instant::now();
+    loop {
+        for app in [&mut server, &mut owner, &mut joiner, &mut intruder] {
+            app.update();
+        }
+        let joined = joiner.world().resource::<Outcome>().0;
+        let intruded = intruder.world().resource::<Outcome>().0;
+        if let (Some(joined), Some(intruded)) = (joined, intruded) {
+            info!("joiner: {joined:?}, intruder: {intruded:?}");
+            assert!(matches!(joined, JoinResult::Accepted { .. }));
+            assert_eq!(intruded, JoinResult::Rejected(JoinRejected::WrongPassword));
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "lobby handshake timed out"
+        );
+        std::thread::sleep(Duration::from_millis(16));
+    }
+}
+
+#[derive(Resource, Default)]
+struct Outcome(Option<JoinResult>);
+
+fn peer<M>(setup: impl IntoSystem<(), (), M>) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, NetworkingPlugins))
+        .init_resource::<Outcome>()
+        .add_systems(Startup, setup)
+        .add_systems(Update, record_outcome);
+    app.finish();
+    app.cleanup();
+    app
+}
+
+fn record_outcome(mut results: EventReader<JoinResult>, mut outcome: ResMut<Outcome>) {
+    for result in results.read() {
+        outcome.0 = Some(*result);
+    }
+}